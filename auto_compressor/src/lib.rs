@@ -13,9 +13,15 @@ use pyo3::{
 };
 use std::str::FromStr;
 
-use synapse_compress_state::Level;
+#[cfg(feature = "sqlite")]
+use synapse_compress_state::sqlite;
+use synapse_compress_state::{Level, SslMode, TlsConfig};
 
+#[cfg(feature = "async")]
+pub mod async_state_saving;
 pub mod manager;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_state_saving;
 pub mod state_saving;
 
 /// Helper struct for parsing the `default_levels` argument.
@@ -70,6 +76,7 @@ fn auto_compressor(_py: Python, m: &PyModule) -> PyResult<()> {
     // ensure any panics produce error messages in the log
     log_panics::init();
 
+    #[allow(clippy::too_many_arguments)]
     #[pyfn(m, compress_largest_rooms)]
     fn compress_state_events_table(
         py: Python,
@@ -77,19 +84,40 @@ fn auto_compressor(_py: Python, m: &PyModule) -> PyResult<()> {
         chunk_size: i64,
         default_levels: String,
         number_of_chunks: i64,
+        sslmode: Option<String>,
+        sslrootcert: Option<String>,
+        sslcert: Option<String>,
+        sslkey: Option<String>,
+        skip_backfill: Option<bool>,
     ) -> PyResult<()> {
         // Stops the compressor from holding the GIL while running
         py.allow_threads(|| {
-            _compress_state_events_table_body(db_url, chunk_size, default_levels, number_of_chunks)
+            _compress_state_events_table_body(
+                db_url,
+                chunk_size,
+                default_levels,
+                number_of_chunks,
+                sslmode,
+                sslrootcert,
+                sslcert,
+                sslkey,
+                skip_backfill,
+            )
         })
     }
 
     // Not accessbile through Py03. It is a "private" function.
+    #[allow(clippy::too_many_arguments)]
     fn _compress_state_events_table_body(
         db_url: String,
         chunk_size: i64,
         default_levels: String,
         number_of_chunks: i64,
+        sslmode: Option<String>,
+        sslrootcert: Option<String>,
+        sslcert: Option<String>,
+        sslkey: Option<String>,
+        skip_backfill: Option<bool>,
     ) -> PyResult<()> {
         // Announce the start of the program to the logs
         log::info!("auto_compressor started");
@@ -105,13 +133,75 @@ fn auto_compressor(_py: Python, m: &PyModule) -> PyResult<()> {
             }
         };
 
-        // call compress_largest_rooms with the arguments supplied
-        let run_result = manager::compress_chunks_of_database(
-            &db_url,
-            chunk_size,
-            &default_levels.0,
-            number_of_chunks,
-        );
+        // Work out how to secure the connection (prefer TLS by default, as
+        // the command line tool does)
+        let sslmode: SslMode = match sslmode.as_deref().unwrap_or("prefer").parse() {
+            Ok(m) => m,
+            Err(e) => {
+                return Err(PyErr::new::<PyRuntimeError, _>(format!(
+                    "Unable to parse sslmode: {}",
+                    e
+                )))
+            }
+        };
+        let tls = match TlsConfig::new(
+            sslmode,
+            sslrootcert.as_deref(),
+            sslcert.as_deref(),
+            sslkey.as_deref(),
+        ) {
+            Ok(tls) => tls,
+            Err(e) => return Err(PyErr::new::<PyRuntimeError, _>(e)),
+        };
+
+        // Connect to the database, which is used for every chunk, and call
+        // compress_largest_rooms with the arguments supplied
+        #[cfg(feature = "sqlite")]
+        if let Some(path) = sqlite::path_from_url(&db_url) {
+            return check_run_result(sqlite_state_saving::connect_to_database(path).map(
+                |mut connection| {
+                    manager::compress_chunks_of_database(
+                        &mut connection,
+                        chunk_size,
+                        &default_levels.0,
+                        number_of_chunks,
+                        skip_backfill.unwrap_or(false),
+                    )
+                },
+            ));
+        }
+
+        if cfg!(not(feature = "sqlite")) && db_url.starts_with("sqlite:") {
+            return Err(PyErr::new::<PyRuntimeError, _>(
+                "SQLite databases need the sqlite feature",
+            ));
+        }
+
+        check_run_result(
+            state_saving::connect_to_database(&db_url, &tls).map(|mut client| {
+                manager::compress_chunks_of_database(
+                    &mut client,
+                    chunk_size,
+                    &default_levels.0,
+                    number_of_chunks,
+                    skip_backfill.unwrap_or(false),
+                )
+            }),
+        )
+    }
+
+    // Turns the result of connecting to the database and compressing it into
+    // the result returned to python, logging any error from compressing
+    fn check_run_result(run_result: Result<Result<()>>) -> PyResult<()> {
+        let run_result = match run_result {
+            Ok(run_result) => run_result,
+            Err(e) => {
+                return Err(PyErr::new::<PyRuntimeError, _>(format!(
+                    "Unable to connect to the database: {}",
+                    e
+                )))
+            }
+        };
 
         // (Note, need to do `{:?}` formatting to show error context)
         // Don't log the context of errors but do use it in the PyRuntimeError
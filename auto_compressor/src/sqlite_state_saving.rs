@@ -0,0 +1,129 @@
+// This module contains SQLite versions of the functions in state_saving, for
+// Synapse instances that use SQLite. The tables and queries are the same
+
+use anyhow::Result;
+use log::trace;
+use rusqlite::{params, Connection, OptionalExtension};
+use synapse_compress_state::{sqlite::try_connect, Level};
+
+use crate::state_saving::{
+    level_columns, levels_from_columns, CompressorStore, LevelColumns, CREATE_TABLES_SQL,
+    GET_NEXT_ROOM_SQL, READ_STATE_SQL, UPDATE_TOTAL_PROGRESS_SQL, WRITE_LEVEL_SQL,
+    WRITE_PROGRESS_SQL,
+};
+
+/// Opens the SQLite database that synapse is using
+///
+/// # Arguments
+///
+/// * `path`            -   The path of the database file, e.g. "homeserver.db"
+pub fn connect_to_database(path: &str) -> Result<Connection> {
+    let connection = try_connect(path)?;
+    Ok(connection)
+}
+
+/// The same as `state_saving::create_tables_if_needed`
+pub fn create_tables_if_needed(connection: &Connection) -> Result<()> {
+    connection.execute_batch(CREATE_TABLES_SQL)?;
+
+    Ok(())
+}
+
+/// The same as `state_saving::read_room_compressor_state`
+pub fn read_room_compressor_state(
+    connection: &Connection,
+    room_id: &str,
+) -> Result<Option<(i64, Vec<Level>)>> {
+    let mut statement = connection.prepare(READ_STATE_SQL)?;
+
+    let levels = statement
+        .query_map(params![room_id], |l| {
+            Ok((
+                l.get("level_num")?,
+                l.get("max_size")?,
+                l.get("current_length")?,
+                l.get("current_head")?,
+                l.get("last_compressed")?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<LevelColumns>>>()?;
+
+    levels_from_columns(room_id, levels)
+}
+
+/// The same as `state_saving::write_room_compressor_state`
+pub fn write_room_compressor_state(
+    connection: &mut Connection,
+    room_id: &str,
+    level_info: &[Level],
+    last_compressed: i64,
+) -> Result<()> {
+    // Wrap all the changes to the state for this room in a transaction
+    // This prevents accidentally having malformed compressor start info
+    let write_transaction = connection.transaction()?;
+
+    for (index, level) in level_info.iter().enumerate() {
+        let (level_num, max_size, current_len, current_head) = level_columns(index, level);
+
+        write_transaction.execute(
+            WRITE_LEVEL_SQL,
+            params![room_id, level_num, max_size, current_len, current_head],
+        )?;
+    }
+
+    write_transaction.execute(WRITE_PROGRESS_SQL, params![room_id, last_compressed])?;
+
+    write_transaction.commit()?;
+
+    Ok(())
+}
+
+/// The same as `state_saving::get_next_room_to_compress`
+pub fn get_next_room_to_compress(connection: &Connection) -> Result<Option<String>> {
+    let next_room = connection
+        .query_row(GET_NEXT_ROOM_SQL, [], |row| {
+            Ok((row.get("room_id")?, row.get("id")?))
+        })
+        .optional()?;
+
+    let (next_room, lowest_uncompressed_group): (String, i64) = match next_room {
+        Some(next_room) => next_room,
+        None => return Ok(None),
+    };
+
+    connection.execute(
+        UPDATE_TOTAL_PROGRESS_SQL,
+        params![lowest_uncompressed_group],
+    )?;
+
+    trace!(
+        "next_room: {}, lowest_uncompressed: {}",
+        next_room,
+        lowest_uncompressed_group
+    );
+
+    Ok(Some(next_room))
+}
+
+impl CompressorStore for Connection {
+    fn create_tables_if_needed(&mut self) -> Result<()> {
+        create_tables_if_needed(self)
+    }
+
+    fn read_room_compressor_state(&mut self, room_id: &str) -> Result<Option<(i64, Vec<Level>)>> {
+        read_room_compressor_state(self, room_id)
+    }
+
+    fn write_room_compressor_state(
+        &mut self,
+        room_id: &str,
+        level_info: &[Level],
+        last_compressed: i64,
+    ) -> Result<()> {
+        write_room_compressor_state(self, room_id, level_info, last_compressed)
+    }
+
+    fn get_next_room_to_compress(&mut self) -> Result<Option<String>> {
+        get_next_room_to_compress(self)
+    }
+}
@@ -0,0 +1,111 @@
+use auto_compressor::async_state_saving::{
+    connect_to_database, create_tables_if_needed, read_room_compressor_state,
+    write_room_compressor_state,
+};
+use compressor_integration_tests::{
+    add_contents_to_database, clear_compressor_state, database_collapsed_states_match_map,
+    database_structure_matches_map, empty_database,
+    map_builder::{compressed_3_3_from_0_to_13_with_state, line_segments_with_state},
+    setup_logger, DB_URL,
+};
+use serial_test::serial;
+use synapse_compress_state::{async_database, Level, LevelStrategy, TlsConfig};
+
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+// The async functions should do exactly the same as the blocking ones, so this
+// repeats continue_run_called_twice_same_as_run with them
+#[test]
+#[serial(db)]
+fn async_continue_run_called_twice_same_as_run() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    block_on(async {
+        let mut client = async_database::connect(DB_URL, &TlsConfig::default()).await;
+
+        let chunk_stats_1 = async_database::continue_run_with_strategy(
+            None,
+            7,
+            &mut client,
+            "room1",
+            Box::new(LevelStrategy::new(&[3, 3])),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(chunk_stats_1.last_compressed_group, 6);
+        assert_eq!(
+            chunk_stats_1.new_level_info,
+            vec![Level::restore(3, 1, Some(6)), Level::restore(3, 2, Some(6))]
+        );
+
+        let chunk_stats_2 = async_database::continue_run_with_strategy(
+            Some(6),
+            7,
+            &mut client,
+            "room1",
+            Box::new(LevelStrategy::from_save(&chunk_stats_1.new_level_info)),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(chunk_stats_2.last_compressed_group, 13);
+    });
+
+    let expected = compressed_3_3_from_0_to_13_with_state();
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected))
+}
+
+#[test]
+#[serial(db)]
+fn async_write_then_read_gives_the_same_levels() {
+    setup_logger();
+
+    // The blocking client used by clear_compressor_state can't be used from
+    // within the runtime
+    block_on(async {
+        let client = connect_to_database(DB_URL, &TlsConfig::default())
+            .await
+            .unwrap();
+        create_tables_if_needed(&client).await.unwrap();
+    });
+    clear_compressor_state();
+
+    let level_info = vec![Level::restore(3, 1, Some(6)), Level::restore(3, 2, Some(6))];
+
+    let saved = block_on(async {
+        let mut client = connect_to_database(DB_URL, &TlsConfig::default())
+            .await
+            .unwrap();
+        write_room_compressor_state(&mut client, "room1", &level_info, 6)
+            .await
+            .unwrap();
+
+        read_room_compressor_state(&client, "room1").await.unwrap()
+    });
+
+    assert_eq!(saved, Some((6, level_info)));
+}
@@ -14,7 +14,7 @@ use compressor_integration_tests::{
     setup_logger, DB_URL,
 };
 use serial_test::serial;
-use synapse_compress_state::Level;
+use synapse_compress_state::{Level, TlsConfig};
 
 #[test]
 #[serial(db)]
@@ -31,7 +31,7 @@ fn run_compressor_on_room_chunk_works() {
     empty_database();
     add_contents_to_database("room1", &initial);
 
-    let mut client = connect_to_database(DB_URL).unwrap();
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
     create_tables_if_needed(&mut client).unwrap();
     clear_compressor_state();
 
@@ -45,11 +45,11 @@ fn run_compressor_on_room_chunk_works() {
     // 0  3\
     // 1  4 6
     // 2  5
-    run_compressor_on_room_chunk(DB_URL, "room1", 7, &default_levels).unwrap();
+    run_compressor_on_room_chunk(&mut client, "room1", 7, &default_levels, false).unwrap();
 
     // compress the next 7 groups
 
-    run_compressor_on_room_chunk(DB_URL, "room1", 7, &default_levels).unwrap();
+    run_compressor_on_room_chunk(&mut client, "room1", 7, &default_levels, false).unwrap();
 
     // This should have created the following structure in the database
     // i.e. groups 6 and 9 should have changed from before
@@ -88,7 +88,7 @@ fn compress_chunks_of_database_compresses_multiple_rooms() {
     add_contents_to_database("room1", &initial1);
     add_contents_to_database("room2", &initial2);
 
-    let mut client = connect_to_database(DB_URL).unwrap();
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
     create_tables_if_needed(&mut client).unwrap();
     clear_compressor_state();
 
@@ -97,7 +97,7 @@ fn compress_chunks_of_database_compresses_multiple_rooms() {
 
     // Compress 4 chunks of size 8.
     // The first two should compress room1 and the second two should compress room2
-    compress_chunks_of_database(DB_URL, 8, &default_levels, 4).unwrap();
+    compress_chunks_of_database(&mut client, 8, &default_levels, 4, false).unwrap();
 
     // We are aiming for the following structure in the database for room1
     // i.e. groups 6 and 9 should have changed from initial map
@@ -165,7 +165,7 @@ fn compress_chunks_of_database_continues_where_it_left_off() {
     add_contents_to_database("room1", &initial1);
     add_contents_to_database("room2", &initial2);
 
-    let mut client = connect_to_database(DB_URL).unwrap();
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
     create_tables_if_needed(&mut client).unwrap();
     clear_compressor_state();
 
@@ -175,12 +175,12 @@ fn compress_chunks_of_database_continues_where_it_left_off() {
     // Compress chunks of various sizes:
     //
     // These two should compress room1
-    compress_chunks_of_database(DB_URL, 8, &default_levels, 1).unwrap();
-    compress_chunks_of_database(DB_URL, 100, &default_levels, 1).unwrap();
+    compress_chunks_of_database(&mut client, 8, &default_levels, 1, false).unwrap();
+    compress_chunks_of_database(&mut client, 100, &default_levels, 1, false).unwrap();
     // These three should compress room2
-    compress_chunks_of_database(DB_URL, 1, &default_levels, 2).unwrap();
-    compress_chunks_of_database(DB_URL, 5, &default_levels, 1).unwrap();
-    compress_chunks_of_database(DB_URL, 5, &default_levels, 1).unwrap();
+    compress_chunks_of_database(&mut client, 1, &default_levels, 2, false).unwrap();
+    compress_chunks_of_database(&mut client, 5, &default_levels, 1, false).unwrap();
+    compress_chunks_of_database(&mut client, 5, &default_levels, 1, false).unwrap();
 
     // We are aiming for the following structure in the database for room1
     // i.e. groups 6 and 9 should have changed from initial map
@@ -0,0 +1,165 @@
+use std::{collections::BTreeMap, env, fs};
+
+use auto_compressor::{
+    manager::compress_chunks_of_database,
+    sqlite_state_saving::{connect_to_database, read_room_compressor_state},
+};
+use compressor_integration_tests::{
+    map_builder::{compressed_3_3_from_0_to_13_with_state, line_segments_with_state},
+    setup_logger,
+};
+use rusqlite::{params, Connection, OptionalExtension};
+use state_map::StateMap;
+use synapse_compress_state::{Level, StateGroupEntry};
+
+/// Creates the tables Synapse keeps the state groups in and copies the state
+/// groups into them
+fn add_contents_to_sqlite(
+    connection: &Connection,
+    room_id: &str,
+    state_group_map: &BTreeMap<i64, StateGroupEntry>,
+) {
+    connection
+        .execute_batch(
+            r#"
+            CREATE TABLE state_groups (
+                id BIGINT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                event_id TEXT NOT NULL
+            );
+            CREATE TABLE state_groups_state (
+                state_group BIGINT NOT NULL,
+                room_id TEXT NOT NULL,
+                type TEXT NOT NULL,
+                state_key TEXT NOT NULL,
+                event_id TEXT NOT NULL
+            );
+            CREATE TABLE state_group_edges (
+                state_group BIGINT NOT NULL,
+                prev_state_group BIGINT NOT NULL
+            );
+            "#,
+        )
+        .unwrap();
+
+    for (sg, entry) in state_group_map {
+        connection
+            .execute(
+                "INSERT INTO state_groups (id, room_id, event_id) VALUES (?1, ?2, 'left_blank')",
+                params![sg, room_id],
+            )
+            .unwrap();
+
+        if let Some(prev_sg) = entry.prev_state_group {
+            connection
+                .execute(
+                    "INSERT INTO state_group_edges (state_group, prev_state_group) VALUES (?1, ?2)",
+                    params![sg, prev_sg],
+                )
+                .unwrap();
+        }
+
+        for ((t, s), e) in entry.state_map.iter() {
+            connection
+                .execute(
+                    "INSERT INTO state_groups_state VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![sg, room_id, t, s, &**e],
+                )
+                .unwrap();
+        }
+    }
+}
+
+/// Checks that the predecessor and delta of every state group in the
+/// database match the map
+fn sqlite_structure_matches_map(
+    connection: &Connection,
+    state_group_map: &BTreeMap<i64, StateGroupEntry>,
+) -> bool {
+    for (sg, entry) in state_group_map {
+        let prev_state_group: Option<i64> = connection
+            .query_row(
+                "SELECT prev_state_group FROM state_group_edges WHERE state_group = ?1",
+                params![sg],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap();
+
+        let mut statement = connection
+            .prepare(
+                "SELECT type, state_key, event_id FROM state_groups_state WHERE state_group = ?1",
+            )
+            .unwrap();
+        let rows = statement
+            .query_map(params![sg], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .unwrap();
+
+        let mut state_map = StateMap::new();
+        for row in rows {
+            let (t, s, e) = row.unwrap();
+            state_map.insert(&t, &s, e.into());
+        }
+
+        if prev_state_group != entry.prev_state_group || state_map != entry.state_map {
+            return false;
+        }
+    }
+
+    true
+}
+
+#[test]
+fn compress_chunks_of_database_works_on_sqlite() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    let path = env::temp_dir().join("compress_chunks_of_database_works_on_sqlite.db");
+    let _ = fs::remove_file(&path);
+
+    let mut connection = connect_to_database(path.to_str().unwrap()).unwrap();
+    add_contents_to_sqlite(&connection, "room1", &initial);
+
+    // compress in 3,3 level sizes by default, in two chunks of 7 groups
+    let default_levels = vec![Level::new(3), Level::new(3)];
+
+    compress_chunks_of_database(&mut connection, 7, &default_levels, 2, false).unwrap();
+
+    // This should have created the following structure in the database
+    //
+    // 0  3\      12
+    // 1  4 6\    13
+    // 2  5 7 9
+    //      8 10
+    //        11
+    let expected = compressed_3_3_from_0_to_13_with_state();
+    assert!(sqlite_structure_matches_map(&connection, &expected));
+
+    // And the compressor should have saved where it got up to
+    let (last_compressed, level_info) = read_room_compressor_state(&connection, "room1")
+        .unwrap()
+        .unwrap();
+    assert_eq!(last_compressed, 13);
+    assert_eq!(
+        level_info,
+        vec![
+            Level::restore(3, 2, Some(13)),
+            Level::restore(3, 1, Some(12))
+        ]
+    );
+
+    fs::remove_file(&path).unwrap();
+}
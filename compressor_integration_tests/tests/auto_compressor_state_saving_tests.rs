@@ -4,13 +4,13 @@ use auto_compressor::state_saving::{
 };
 use compressor_integration_tests::{clear_compressor_state, setup_logger, DB_URL};
 use serial_test::serial;
-use synapse_compress_state::Level;
+use synapse_compress_state::{Level, TlsConfig};
 
 #[test]
 #[serial(db)]
 fn write_then_read_state_gives_correct_results() {
     setup_logger();
-    let mut client = connect_to_database(DB_URL).unwrap();
+    let mut client = connect_to_database(DB_URL, &TlsConfig::default()).unwrap();
     create_tables_if_needed(&mut client).unwrap();
     clear_compressor_state();
 
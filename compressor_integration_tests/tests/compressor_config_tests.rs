@@ -1,16 +1,23 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    panic::{self, AssertUnwindSafe},
+};
 
 use compressor_integration_tests::{
     add_contents_to_database, database_collapsed_states_match_map, database_structure_matches_map,
-    empty_database,
+    empty_database, get_commit_progress, make_changes_to_group_fail,
     map_builder::{
         compressed_3_3_from_0_to_13_with_state, line_segments_with_state, line_with_state,
         structure_from_edges_with_state,
     },
-    setup_logger, DB_URL,
+    set_commit_progress, setup_logger, ConfigArgs,
 };
 use serial_test::serial;
-use synapse_compress_state::{run, Config};
+use state_map::StateMap;
+use synapse_compress_state::{
+    run, run_rooms, CompressionStrategy, DuplicateMode, DuplicatesStrategy, LevelStrategy,
+    MultiRoomConfig, RoomSelection,
+};
 
 // Remember to add #[serial(db)] before any test that access the database.
 // Only one test with this annotation can run at once - preventing
@@ -35,31 +42,12 @@ fn run_succeeds_without_crashing() {
     empty_database();
     add_contents_to_database("room1", &initial);
 
-    let db_url = DB_URL.to_string();
-    let room_id = "room1".to_string();
-    let output_file = Some("./tests/tmp/run_succeeds_without_crashing.sql".to_string());
-    let min_state_group = None;
-    let groups_to_compress = None;
-    let min_saved_rows = None;
-    let max_state_group = None;
-    let level_sizes = "3,3".to_string();
-    let transactions = true;
-    let graphs = false;
-    let commit_changes = false;
-
-    let config = Config::new(
-        db_url.clone(),
-        room_id.clone(),
-        output_file,
-        min_state_group,
-        groups_to_compress,
-        min_saved_rows,
-        max_state_group,
-        level_sizes,
-        transactions,
-        graphs,
-        commit_changes,
-    )
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/run_succeeds_without_crashing.sql".to_string()),
+        commit_changes: false,
+        ..ConfigArgs::default()
+    }
+    .build()
     .unwrap();
 
     run(config);
@@ -83,31 +71,11 @@ fn changes_commited_if_no_min_saved_rows() {
     add_contents_to_database("room1", &initial);
 
     // set up the config options
-    let db_url = DB_URL.to_string();
-    let room_id = "room1".to_string();
-    let output_file = Some("./tests/tmp/changes_commited_if_no_min_saved_rows.sql".to_string());
-    let min_state_group = None;
-    let min_saved_rows = None;
-    let groups_to_compress = None;
-    let max_state_group = None;
-    let level_sizes = "3,3".to_string();
-    let transactions = true;
-    let graphs = false;
-    let commit_changes = true;
-
-    let config = Config::new(
-        db_url,
-        room_id,
-        output_file,
-        min_state_group,
-        groups_to_compress,
-        min_saved_rows,
-        max_state_group,
-        level_sizes,
-        transactions,
-        graphs,
-        commit_changes,
-    )
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/changes_commited_if_no_min_saved_rows.sql".to_string()),
+        ..ConfigArgs::default()
+    }
+    .build()
     .unwrap();
 
     // Run the compressor with those settings
@@ -149,31 +117,12 @@ fn changes_commited_if_min_saved_rows_exceeded() {
     add_contents_to_database("room1", &initial);
 
     // set up the config options
-    let db_url = DB_URL.to_string();
-    let room_id = "room1".to_string();
-    let output_file = Some("./tests/tmp/changes_commited_if_no_min_saved_rows.sql".to_string());
-    let min_state_group = None;
-    let min_saved_rows = Some(10);
-    let groups_to_compress = None;
-    let max_state_group = None;
-    let level_sizes = "3,3".to_string();
-    let transactions = true;
-    let graphs = false;
-    let commit_changes = true;
-
-    let config = Config::new(
-        db_url,
-        room_id,
-        output_file,
-        min_state_group,
-        groups_to_compress,
-        min_saved_rows,
-        max_state_group,
-        level_sizes,
-        transactions,
-        graphs,
-        commit_changes,
-    )
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/changes_commited_if_no_min_saved_rows.sql".to_string()),
+        min_saved_rows: Some(10),
+        ..ConfigArgs::default()
+    }
+    .build()
     .unwrap();
 
     // Run the compressor with those settings
@@ -215,32 +164,14 @@ fn changes_not_commited_if_fewer_than_min_saved_rows() {
     add_contents_to_database("room1", &initial);
 
     // set up the config options
-    let db_url = DB_URL.to_string();
-    let room_id = "room1".to_string();
-    let output_file =
-        Some("./tests/tmp/changes_not_commited_if_fewer_than_min_saved_rows.sql".to_string());
-    let min_state_group = None;
-    let min_saved_rows = Some(12);
-    let groups_to_compress = None;
-    let max_state_group = None;
-    let level_sizes = "3,3".to_string();
-    let transactions = true;
-    let graphs = false;
-    let commit_changes = true;
-
-    let config = Config::new(
-        db_url,
-        room_id,
-        output_file,
-        min_state_group,
-        groups_to_compress,
-        min_saved_rows,
-        max_state_group,
-        level_sizes,
-        transactions,
-        graphs,
-        commit_changes,
-    )
+    let config = ConfigArgs {
+        output_file: Some(
+            "./tests/tmp/changes_not_commited_if_fewer_than_min_saved_rows.sql".to_string(),
+        ),
+        min_saved_rows: Some(12),
+        ..ConfigArgs::default()
+    }
+    .build()
     .unwrap();
 
     // Run the compressor with those settings
@@ -269,31 +200,12 @@ fn changes_not_commited_if_fewer_than_min_saved_rows() {
 fn run_panics_if_invalid_db_url() {
     setup_logger();
     // set up the config options
-    let db_url = "thisIsAnInvalidURL".to_string();
-    let room_id = "room1".to_string();
-    let output_file = Some("./tests/tmp/run_panics_if_invalid_db_url.sql".to_string());
-    let min_state_group = None;
-    let min_saved_rows = None;
-    let groups_to_compress = None;
-    let max_state_group = None;
-    let level_sizes = "3,3".to_string();
-    let transactions = true;
-    let graphs = false;
-    let commit_changes = true;
-
-    let config = Config::new(
-        db_url,
-        room_id,
-        output_file,
-        min_state_group,
-        groups_to_compress,
-        min_saved_rows,
-        max_state_group,
-        level_sizes,
-        transactions,
-        graphs,
-        commit_changes,
-    )
+    let config = ConfigArgs {
+        db_url: "thisIsAnInvalidURL".to_string(),
+        output_file: Some("./tests/tmp/run_panics_if_invalid_db_url.sql".to_string()),
+        ..ConfigArgs::default()
+    }
+    .build()
     .unwrap();
 
     // Run the compressor with those settings
@@ -325,31 +237,11 @@ fn run_only_affects_given_room_id() {
     add_contents_to_database("room2", &initial_room_2);
 
     // set up the config options
-    let db_url = DB_URL.to_string();
-    let room_id = "room1".to_string();
-    let output_file = Some("./tests/tmp/run_only_affects_given_room_id.sql".to_string());
-    let min_state_group = None;
-    let min_saved_rows = None;
-    let groups_to_compress = None;
-    let max_state_group = None;
-    let level_sizes = "3,3".to_string();
-    let transactions = true;
-    let graphs = false;
-    let commit_changes = true;
-
-    let config = Config::new(
-        db_url,
-        room_id,
-        output_file,
-        min_state_group,
-        groups_to_compress,
-        min_saved_rows,
-        max_state_group,
-        level_sizes,
-        transactions,
-        graphs,
-        commit_changes,
-    )
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/run_only_affects_given_room_id.sql".to_string()),
+        ..ConfigArgs::default()
+    }
+    .build()
     .unwrap();
 
     // Run the compressor with those settings
@@ -395,31 +287,13 @@ fn run_respects_groups_to_compress() {
     add_contents_to_database("room1", &initial);
 
     // set up the config options
-    let db_url = DB_URL.to_string();
-    let room_id = "room1".to_string();
-    let output_file = Some("./tests/tmp/run_respects_groups_to_compress.sql".to_string());
-    let min_state_group = Some(2);
-    let min_saved_rows = None;
-    let groups_to_compress = Some(9);
-    let max_state_group = None;
-    let level_sizes = "3,3".to_string();
-    let transactions = true;
-    let graphs = false;
-    let commit_changes = true;
-
-    let config = Config::new(
-        db_url,
-        room_id,
-        output_file,
-        min_state_group,
-        groups_to_compress,
-        min_saved_rows,
-        max_state_group,
-        level_sizes,
-        transactions,
-        graphs,
-        commit_changes,
-    )
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/run_respects_groups_to_compress.sql".to_string()),
+        min_state_group: Some(2),
+        groups_to_compress: Some(9),
+        ..ConfigArgs::default()
+    }
+    .build()
     .unwrap();
 
     // Run the compressor with those settings
@@ -460,6 +334,320 @@ fn run_respects_groups_to_compress() {
     assert!(database_structure_matches_map(&expected))
 }
 
+#[test]
+#[serial(db)]
+fn run_respects_max_depth() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/run_respects_max_depth.sql".to_string()),
+        max_depth: Some(4),
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    // Run the compressor with those settings
+    run(config);
+
+    // The levels would put 11 at the end of the chain 11-10-9-6-3, so it is
+    // moved onto 9 to keep the chain at most 4 groups long. This should have
+    // created the following structure in the database
+    //
+    // 0  3\      12
+    // 1  4 6\    13
+    // 2  5 7 9\
+    //      8 10 11
+    let expected_edges: BTreeMap<i64, i64> = vec![
+        (1, 0),
+        (2, 1),
+        (4, 3),
+        (5, 4),
+        (6, 3),
+        (7, 6),
+        (8, 7),
+        (9, 6),
+        (10, 9),
+        (11, 9),
+        (13, 12),
+    ]
+    .into_iter()
+    .collect();
+
+    let expected = structure_from_edges_with_state(expected_edges, 0, 13);
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected))
+}
+
+#[test]
+#[serial(db)]
+fn run_with_compact_gives_same_structure() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/run_with_compact_gives_same_structure.sql".to_string()),
+        compact: true,
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    // Run the compressor with those settings
+    run(config);
+
+    // Holding the state groups in the compact representation shouldn't change
+    // the result, so this should have created the following structure in the
+    // database
+    //
+    // 0  3\      12
+    // 1  4 6\    13
+    // 2  5 7 9
+    //      8 10
+    //        11
+    let expected_edges: BTreeMap<i64, i64> = vec![
+        (1, 0),
+        (2, 1),
+        (4, 3),
+        (5, 4),
+        (6, 3),
+        (7, 6),
+        (8, 7),
+        (9, 6),
+        (10, 9),
+        (11, 10),
+        (13, 12),
+    ]
+    .into_iter()
+    .collect();
+
+    let expected = structure_from_edges_with_state(expected_edges, 0, 13);
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected))
+}
+
+#[test]
+#[serial(db)]
+fn run_with_copy_gives_same_structure() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/run_with_copy_gives_same_structure.sql".to_string()),
+        copy: true,
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    // Run the compressor with those settings
+    run(config);
+
+    // Loading the state groups with COPY shouldn't change the result, so this should have created the following structure in the
+    // database
+    //
+    // 0  3\      12
+    // 1  4 6\    13
+    // 2  5 7 9
+    //      8 10
+    //        11
+    let expected_edges: BTreeMap<i64, i64> = vec![
+        (1, 0),
+        (2, 1),
+        (4, 3),
+        (5, 4),
+        (6, 3),
+        (7, 6),
+        (8, 7),
+        (9, 6),
+        (10, 9),
+        (11, 10),
+        (13, 12),
+    ]
+    .into_iter()
+    .collect();
+
+    let expected = structure_from_edges_with_state(expected_edges, 0, 13);
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected))
+}
+
+#[test]
+#[serial(db)]
+fn run_with_stream_gives_same_structure() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/run_with_stream_gives_same_structure.sql".to_string()),
+        stream: true,
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    // Run the compressor with those settings
+    run(config);
+
+    // The state of each group is a line, so the levels always suggest a valid
+    // base and streaming shouldn't change the result. This should have created
+    // the following structure in the database
+    //
+    // 0  3\      12
+    // 1  4 6\    13
+    // 2  5 7 9
+    //      8 10
+    //        11
+    let expected_edges: BTreeMap<i64, i64> = vec![
+        (1, 0),
+        (2, 1),
+        (4, 3),
+        (5, 4),
+        (6, 3),
+        (7, 6),
+        (8, 7),
+        (9, 6),
+        (10, 9),
+        (11, 10),
+        (13, 12),
+    ]
+    .into_iter()
+    .collect();
+
+    let expected = structure_from_edges_with_state(expected_edges, 0, 13);
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected))
+}
+
+#[test]
+#[serial(db)]
+fn run_with_stream_leaves_out_batches_that_save_too_few_rows() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let config = ConfigArgs {
+        min_saved_rows: Some(1000),
+        stream: true,
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    // Run the compressor with those settings
+    let summary = run(config);
+
+    // All the groups fit in one batch, which doesn't save 1000 rows, so
+    // nothing is changed
+    assert_eq!(summary.state_groups_changed, 0);
+    assert_eq!(summary.new_num_rows, summary.original_num_rows);
+    assert!(database_structure_matches_map(&initial));
+}
+
+#[test]
+#[serial(db)]
+#[should_panic(expected = "No state groups found within this range")]
+fn run_with_stream_panics_if_no_groups_after_min_state_group() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let config = ConfigArgs {
+        min_state_group: Some(13),
+        stream: true,
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    // There are no groups after 13, even though it is the last group of the
+    // room (which is what is found without -n)
+    run(config);
+}
+
 #[test]
 #[serial(db)]
 fn run_is_idempotent_when_run_on_whole_room() {
@@ -478,49 +666,18 @@ fn run_is_idempotent_when_run_on_whole_room() {
     add_contents_to_database("room1", &initial);
 
     // set up the config options
-    let db_url = DB_URL.to_string();
-    let room_id = "room1".to_string();
-    let output_file1 =
-        Some("./tests/tmp/run_is_idempotent_when_run_on_whole_room_1.sql".to_string());
-    let output_file2 =
-        Some("./tests/tmp/run_is_idempotent_when_run_on_whole_room_2.sql".to_string());
-    let min_state_group = None;
-    let min_saved_rows = None;
-    let groups_to_compress = None;
-    let max_state_group = None;
-    let level_sizes = "3,3".to_string();
-    let transactions = true;
-    let graphs = false;
-    let commit_changes = true;
-
-    let config1 = Config::new(
-        db_url.clone(),
-        room_id.clone(),
-        output_file1,
-        min_state_group,
-        groups_to_compress,
-        min_saved_rows,
-        max_state_group,
-        level_sizes.clone(),
-        transactions,
-        graphs,
-        commit_changes,
-    )
+    let config1 = ConfigArgs {
+        output_file: Some("./tests/tmp/run_is_idempotent_when_run_on_whole_room_1.sql".to_string()),
+        ..ConfigArgs::default()
+    }
+    .build()
     .unwrap();
 
-    let config2 = Config::new(
-        db_url.clone(),
-        room_id.clone(),
-        output_file2,
-        min_state_group,
-        groups_to_compress,
-        min_saved_rows,
-        max_state_group,
-        level_sizes.clone(),
-        transactions,
-        graphs,
-        commit_changes,
-    )
+    let config2 = ConfigArgs {
+        output_file: Some("./tests/tmp/run_is_idempotent_when_run_on_whole_room_2.sql".to_string()),
+        ..ConfigArgs::default()
+    }
+    .build()
     .unwrap();
 
     // We are aiming for the following structure in the database
@@ -556,3 +713,315 @@ fn run_is_idempotent_when_run_on_whole_room() {
     // Check that the structure of the database still matches the expected structure
     assert!(database_structure_matches_map(&expected));
 }
+
+#[test]
+#[serial(db)]
+fn run_rooms_compresses_every_room() {
+    setup_logger();
+    // This starts with two rooms with the following structures
+    //
+    // room1: 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    // room2: 14-15-16 17-18-19 20-21-22 23-24-25 26-27
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i in the same room
+    let room1 = line_segments_with_state(0, 13);
+    let room2 = line_segments_with_state(14, 27);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &room1);
+    add_contents_to_database("room2", &room2);
+
+    // set up the config options
+    let config = ConfigArgs {
+        room_id: String::new(),
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    // room3 doesn't exist, so should fail without stopping the others
+    let rooms = RoomSelection::Listed(vec![
+        "room1".to_string(),
+        "room2".to_string(),
+        "room3".to_string(),
+    ]);
+    let output_path = Some("./tests/tmp/run_rooms_compresses_every_room.sql".to_string());
+
+    // Run the compressor with those settings
+    let summaries = run_rooms(MultiRoomConfig::new(config, rooms, output_path, 2));
+
+    let room_ids: Vec<&str> = summaries.iter().map(|s| s.room_id.as_str()).collect();
+    assert_eq!(room_ids, vec!["room1", "room2", "room3"]);
+
+    for summary in &summaries[..2] {
+        let run_summary = summary.result.as_ref().unwrap();
+        assert_eq!(run_summary.state_groups, 14);
+        assert!(run_summary.state_groups_changed > 0);
+        assert!(run_summary.changes_written);
+    }
+    assert!(summaries[2].result.is_err());
+
+    // Each room gets its own output file
+    assert!(std::path::Path::new("./tests/tmp/run_rooms_compresses_every_room.room1.sql").exists());
+    assert!(std::path::Path::new("./tests/tmp/run_rooms_compresses_every_room.room2.sql").exists());
+
+    // Check that the database still gives correct states for each group!
+    let mut initial = room1;
+    initial.extend(room2);
+    assert!(database_collapsed_states_match_map(&initial));
+}
+
+#[test]
+#[serial(db)]
+fn flatten_chain_undoes_compression() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0  3\      12
+    // 1  4 6\    13
+    // 2  5 7 9
+    //      8 10
+    //        11
+    let initial = compressed_3_3_from_0_to_13_with_state();
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/flatten_chain_undoes_compression.sql".to_string()),
+        flatten: Some("chain".to_string()),
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    // Run the compressor with those settings
+    let summary = run(config);
+
+    // The changes are written out whether or not they save rows (here they
+    // do, as each group only adds two rows to the state of the one before)
+    assert!(summary.new_num_rows < summary.original_num_rows);
+    assert!(summary.changes_written);
+
+    // This should have created the following structure in the database
+    //
+    // 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+    let expected = line_with_state(0, 13);
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected))
+}
+
+#[test]
+#[serial(db)]
+fn run_leaves_pinned_groups_alone() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5-6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let mut initial = line_segments_with_state(0, 13);
+    let six = initial.get_mut(&6).unwrap();
+    six.prev_state_group = Some(5);
+    six.state_map = StateMap::new();
+    six.state_map.insert("group", "6", "seen".into());
+    six.state_map.insert("node", "is", "6".into());
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/run_leaves_pinned_groups_alone.sql".to_string()),
+        pin: Some("6,10".to_string()),
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    // Run the compressor with those settings
+    let summary = run(config);
+
+    // The rest of the room was still compressed
+    assert!(summary.changes_written);
+    assert!(summary.state_groups_changed > 0);
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that 6 and 10 still have exactly the predecessor and delta they
+    // started with (based on 5 and 9)
+    let pinned: BTreeMap<_, _> = initial
+        .iter()
+        .filter(|(sg, _)| **sg == 6 || **sg == 10)
+        .map(|(sg, entry)| (*sg, entry.clone()))
+        .collect();
+    assert!(database_structure_matches_map(&pinned))
+}
+
+#[test]
+#[serial(db)]
+fn run_merges_duplicate_groups() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    //
+    // except that the snapshots 3 and 9 have the same state as 2 and 8, and
+    // 4 has an empty delta (so has the same state as 2 too)
+    let mut initial = line_segments_with_state(0, 13);
+    for sg in &[3, 9] {
+        let mut state_map = StateMap::new();
+        for j in 0..*sg {
+            state_map.insert("group", &j.to_string(), "seen".into());
+        }
+        state_map.insert("node", "is", (sg - 1).to_string().into());
+
+        initial.get_mut(sg).unwrap().state_map = state_map;
+    }
+    initial.get_mut(&4).unwrap().state_map = StateMap::new();
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let config = ConfigArgs {
+        output_file: Some("./tests/tmp/run_merges_duplicate_groups.sql".to_string()),
+        level_sizes: "20".to_string(),
+        duplicates: Some("merge".to_string()),
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    // Run the compressor with those settings
+    run(config);
+
+    // A level this big puts the groups back into a line, so 3 and 9 end up
+    // with empty deltas on 2 and 8. 4 would be left on 3, but instead it
+    // should point at 2 (which doesn't make its chain any longer)
+    let inner = Box::new(LevelStrategy::new(&[20]));
+    let (expected, _) = DuplicatesStrategy::new(inner, DuplicateMode::Merge).compress(&initial);
+
+    for (sg, canonical_sg) in &[(3, 2), (4, 2), (9, 8)] {
+        assert_eq!(expected[sg].prev_state_group, Some(*canonical_sg));
+        assert!(expected[sg].state_map.is_empty());
+    }
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected))
+}
+
+#[test]
+#[serial(db)]
+fn run_resumes_partly_committed_changes() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+    let expected = compressed_3_3_from_0_to_13_with_state();
+
+    // Pretend that an earlier run was interrupted after committing the
+    // changes up to group 6
+    let mut partly_committed = initial.clone();
+    for sg in 0..=6 {
+        partly_committed.insert(sg, expected[&sg].clone());
+    }
+
+    empty_database();
+    add_contents_to_database("room1", &partly_committed);
+    set_commit_progress("room1", "some other plan", 6);
+
+    // set up the config options
+    let config = ConfigArgs {
+        resume: true,
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    // The plan doesn't match so the changes are worked out again, but as the
+    // compressor only looks at the full state of each group they are the same
+    run(config);
+
+    // The rest of the changes are committed and the progress is cleared
+    assert!(database_collapsed_states_match_map(&initial));
+    assert!(database_structure_matches_map(&expected));
+    assert_eq!(get_commit_progress("room1"), None);
+}
+
+#[test]
+#[serial(db)]
+fn run_resumes_with_the_plan_of_the_interrupted_run() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+    let expected = compressed_3_3_from_0_to_13_with_state();
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // Compressing with 3,3 levels changes groups 6 and 9, so failing to change
+    // group 9 interrupts the run after committing group 6
+    make_changes_to_group_fail(Some(9));
+
+    // set up the config options
+    let config = ConfigArgs::default().build().unwrap();
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| run(config)));
+    assert!(result.is_err());
+
+    make_changes_to_group_fail(None);
+    assert_eq!(get_commit_progress("room1").unwrap().1, Some(6));
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Resume with different levels, which would give different changes if the
+    // room was compressed again
+
+    let config = ConfigArgs {
+        level_sizes: "2,2".to_string(),
+        resume: true,
+        ..ConfigArgs::default()
+    }
+    .build()
+    .unwrap();
+
+    run(config);
+
+    // The rest of the interrupted run's changes are committed and the progress
+    // is cleared
+    assert!(database_collapsed_states_match_map(&initial));
+    assert!(database_structure_matches_map(&expected));
+    assert_eq!(get_commit_progress("room1"), None);
+}
@@ -5,7 +5,9 @@ use compressor_integration_tests::{
     setup_logger, DB_URL,
 };
 use serial_test::serial;
-use synapse_compress_state::{continue_run, Level};
+use synapse_compress_state::{
+    connect, continue_run, CompressorOptions, CopyLoader, Level, Strategy, TlsConfig,
+};
 
 // Tests the saving and continuing functionality
 // The compressor should produce the same results when run in one go
@@ -27,7 +29,7 @@ fn continue_run_called_twice_same_as_run() {
     empty_database();
     add_contents_to_database("room1", &initial);
 
-    let db_url = DB_URL.to_string();
+    let mut client = connect(DB_URL, &TlsConfig::default());
     let room_id = "room1".to_string();
 
     // will run the compression in two batches
@@ -39,7 +41,16 @@ fn continue_run_called_twice_same_as_run() {
     let level_info = vec![Level::new(3), Level::new(3)];
 
     // Run the compressor with those settings
-    let chunk_stats_1 = continue_run(start, chunk_size, &db_url, &room_id, &level_info).unwrap();
+    let chunk_stats_1 = continue_run(
+        start,
+        chunk_size,
+        &mut client,
+        &room_id,
+        &level_info,
+        Strategy::Level,
+        &CompressorOptions::default(),
+    )
+    .unwrap();
 
     // Assert that it stopped at 6 (i.e. after the 7 groups 0...6)
     assert_eq!(chunk_stats_1.last_compressed_group, 6);
@@ -54,12 +65,26 @@ fn continue_run_called_twice_same_as_run() {
         vec![Level::restore(3, 1, Some(6)), Level::restore(3, 2, Some(6))]
     );
 
+    // Only 6 was changed (from being a snapshot to being based on 3) so the
+    // worst case stays at 3 iterations (e.g. for 2 and 5)
+    assert_eq!(chunk_stats_1.query_cost_before.max, 3);
+    assert_eq!(chunk_stats_1.query_cost_after.max, 3);
+
     let start = Some(6);
     let chunk_size = 7;
     let level_info = chunk_stats_1.new_level_info.clone();
 
     // Run the compressor with those settings
-    let chunk_stats_2 = continue_run(start, chunk_size, &db_url, &room_id, &level_info).unwrap();
+    let chunk_stats_2 = continue_run(
+        start,
+        chunk_size,
+        &mut client,
+        &room_id,
+        &level_info,
+        Strategy::Level,
+        &CompressorOptions::default(),
+    )
+    .unwrap();
 
     // Assert that it stopped at 7
     assert_eq!(chunk_stats_2.last_compressed_group, 13);
@@ -81,3 +106,74 @@ fn continue_run_called_twice_same_as_run() {
     // Check that the structure of the database matches the expected structure
     assert!(database_structure_matches_map(&expected))
 }
+
+// Loading the state groups with COPY shouldn't change anything, including
+// when continuing from a previous chunk
+#[test]
+#[serial(db)]
+fn continue_run_with_copy_loader_same_as_run() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    let mut client = connect(DB_URL, &TlsConfig::default());
+    let mut loader = CopyLoader::new(&mut client);
+    let room_id = "room1".to_string();
+
+    // Run the compressor in two batches of 7 with 3,3 level sizes
+    let chunk_stats_1 = continue_run(
+        None,
+        7,
+        &mut loader,
+        &room_id,
+        &[Level::new(3), Level::new(3)],
+        Strategy::Level,
+        &CompressorOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(chunk_stats_1.last_compressed_group, 6);
+    assert_eq!(
+        chunk_stats_1.new_level_info,
+        vec![Level::restore(3, 1, Some(6)), Level::restore(3, 2, Some(6))]
+    );
+
+    let chunk_stats_2 = continue_run(
+        Some(6),
+        7,
+        &mut loader,
+        &room_id,
+        &chunk_stats_1.new_level_info,
+        Strategy::Level,
+        &CompressorOptions::default(),
+    )
+    .unwrap();
+
+    assert_eq!(chunk_stats_2.last_compressed_group, 13);
+
+    // This should have created the same structure as loading the state groups
+    // with a query does
+    //
+    // 0  3\      12
+    // 1  4 6\    13
+    // 2  5 7 9
+    //      8 10
+    //        11
+    let expected = compressed_3_3_from_0_to_13_with_state();
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected))
+}
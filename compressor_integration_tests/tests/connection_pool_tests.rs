@@ -0,0 +1,55 @@
+use compressor_integration_tests::{setup_logger, DB_URL};
+use serial_test::serial;
+use synapse_compress_state::ConnectionPool;
+
+#[test]
+#[serial(db)]
+fn connection_pool_reuses_returned_connections() {
+    setup_logger();
+    let pool = ConnectionPool::new(DB_URL);
+
+    let first_pid: i32 = pool
+        .get()
+        .query_one("SELECT pg_backend_pid()", &[])
+        .unwrap()
+        .get(0);
+
+    // The first connection was dropped so it should be handed out again
+    let second_pid: i32 = pool
+        .get()
+        .query_one("SELECT pg_backend_pid()", &[])
+        .unwrap()
+        .get(0);
+
+    assert_eq!(first_pid, second_pid);
+
+    // Whereas two at once have to be different connections
+    let mut client1 = pool.get();
+    let mut client2 = pool.get();
+    let pid1: i32 = client1
+        .query_one("SELECT pg_backend_pid()", &[])
+        .unwrap()
+        .get(0);
+    let pid2: i32 = client2
+        .query_one("SELECT pg_backend_pid()", &[])
+        .unwrap()
+        .get(0);
+
+    assert_ne!(pid1, pid2);
+}
+
+#[test]
+#[serial(db)]
+fn connection_pool_runs_session_sql_on_new_connections() {
+    setup_logger();
+    let pool = ConnectionPool::new(DB_URL)
+        .with_session_sql("SET application_name = 'connection_pool_test'");
+
+    let application_name: String = pool
+        .get()
+        .query_one("SHOW application_name", &[])
+        .unwrap()
+        .get(0);
+
+    assert_eq!(application_name, "connection_pool_test");
+}
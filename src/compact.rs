@@ -0,0 +1,628 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A memory efficient way of holding the state groups of a room.
+//!
+//! A `BTreeMap<i64, StateGroupEntry>` stores a separate `StateMap` for every
+//! state group, which for big rooms adds up to many gigabytes. Instead, every
+//! `(type, state_key)` pair and every event ID is given an integer ID the first
+//! time it is seen, so each row of state only takes up 8 bytes. The groups are
+//! then stored next to each other in a single vector, sorted by their ID.
+
+use state_map::StateMap;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+use string_cache::DefaultAtom as Atom;
+
+use crate::{StateGroupEntry, StateGroupMap};
+
+/// Hands out integer IDs for the `(type, state_key)` pairs and event IDs found
+/// in the state groups
+#[derive(Default, Debug)]
+pub struct Interner {
+    keys: Vec<(Atom, Atom)>,
+    key_ids: HashMap<(Atom, Atom), u32>,
+    values: Vec<Atom>,
+    value_ids: HashMap<Atom, u32>,
+}
+
+impl Interner {
+    /// Returns the ID of a `(type, state_key)` pair, adding it if it is new
+    pub fn intern_key(&mut self, t: &str, s: &str) -> u32 {
+        let key = (Atom::from(t), Atom::from(s));
+        if let Some(id) = self.key_ids.get(&key) {
+            return *id;
+        }
+
+        let id = self.keys.len() as u32;
+        self.keys.push(key.clone());
+        self.key_ids.insert(key, id);
+        id
+    }
+
+    /// Returns the ID of an event ID, adding it if it is new
+    pub fn intern_value(&mut self, e: &str) -> u32 {
+        let value = Atom::from(e);
+        if let Some(id) = self.value_ids.get(&value) {
+            return *id;
+        }
+
+        let id = self.values.len() as u32;
+        self.values.push(value.clone());
+        self.value_ids.insert(value, id);
+        id
+    }
+
+    /// Gets the `(type, state_key)` pair with the given ID
+    pub fn key(&self, id: u32) -> (&str, &str) {
+        let (t, s) = &self.keys[id as usize];
+        (t, s)
+    }
+
+    /// Gets the event ID with the given ID
+    pub fn value(&self, id: u32) -> &Atom {
+        &self.values[id as usize]
+    }
+
+    /// Turns a `StateMap` into rows of IDs, adding any new keys or values
+    fn encode(&mut self, state_map: &StateMap<Atom>) -> CompactStateMap {
+        CompactStateMap::from_rows(
+            state_map
+                .iter()
+                .map(|((t, s), e)| (self.intern_key(t, s), self.intern_value(e)))
+                .collect(),
+        )
+    }
+
+    /// Turns rows of IDs back into a `StateMap`
+    fn decode(&self, state_map: &CompactStateMap) -> StateMap<Atom> {
+        state_map
+            .iter()
+            .map(|(k, v)| (self.key(k), self.value(v).clone()))
+            .collect()
+    }
+}
+
+/// The state (or the delta) of a single state group, as a list of
+/// `(key ID, value ID)` rows sorted by key ID
+#[derive(Default, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CompactStateMap(Box<[(u32, u32)]>);
+
+impl CompactStateMap {
+    /// Builds a state map from rows in any order. If there is more than one row
+    /// for a key then the last one is kept
+    pub fn from_rows(mut rows: Vec<(u32, u32)>) -> CompactStateMap {
+        // sort_by_key is stable so the rows for each key stay in order
+        rows.sort_by_key(|(k, _)| *k);
+
+        let mut deduped: Vec<(u32, u32)> = Vec::with_capacity(rows.len());
+        for row in rows {
+            match deduped.last_mut() {
+                Some(last) if last.0 == row.0 => *last = row,
+                _ => deduped.push(row),
+            }
+        }
+
+        CompactStateMap(deduped.into_boxed_slice())
+    }
+
+    /// The number of rows
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether there are no rows
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Iterates through the `(key ID, value ID)` rows in order of key ID
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.0.iter().copied()
+    }
+
+    /// Applies a delta on top of this state
+    fn apply(&mut self, delta: &CompactStateMap) {
+        if delta.is_empty() {
+            return;
+        }
+
+        let mut merged = Vec::with_capacity(self.len() + delta.len());
+        let mut ours = self.0.iter().copied().peekable();
+        let mut theirs = delta.iter().peekable();
+
+        loop {
+            match (ours.peek(), theirs.peek()) {
+                (Some(a), Some(b)) if a.0 < b.0 => merged.extend(ours.next()),
+                (Some(a), Some(b)) if a.0 == b.0 => {
+                    ours.next();
+                    merged.extend(theirs.next());
+                }
+                (_, Some(_)) => merged.extend(theirs.next()),
+                (Some(_), None) => merged.extend(ours.next()),
+                (None, None) => break,
+            }
+        }
+
+        self.0 = merged.into_boxed_slice();
+    }
+
+    /// Works out the delta needed to get from `prev` to `state`. Returns None
+    /// if `prev` has keys that `state` doesn't (as deltas can only add to or
+    /// replace the state of their predecessor)
+    fn delta(prev: &CompactStateMap, state: &CompactStateMap) -> Option<CompactStateMap> {
+        let mut delta = Vec::new();
+        let mut prev_rows = prev.iter().peekable();
+
+        for (k, v) in state.iter() {
+            // Every key in prev that is lower than this one isn't in state
+            if matches!(prev_rows.peek(), Some((pk, _)) if *pk < k) {
+                return None;
+            }
+
+            match prev_rows.peek() {
+                Some((pk, pv)) if *pk == k => {
+                    if *pv != v {
+                        delta.push((k, v));
+                    }
+                    prev_rows.next();
+                }
+                _ => delta.push((k, v)),
+            }
+        }
+
+        if prev_rows.next().is_some() {
+            return None;
+        }
+
+        Some(CompactStateMap(delta.into_boxed_slice()))
+    }
+}
+
+/// A single state group in a `CompactStateGroupMap`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CompactEntry {
+    state_group: i64,
+    in_range: bool,
+    prev_state_group: Option<i64>,
+    state_map: CompactStateMap,
+}
+
+/// Holds the same information as a `BTreeMap<i64, StateGroupEntry>` but using
+/// a lot less memory (see the module documentation)
+///
+/// Maps that are derived from each other (e.g. the original state groups and
+/// the compressed ones) share the same `Interner`, so that their rows can be
+/// compared directly.
+#[derive(Debug, Clone)]
+pub struct CompactStateGroupMap {
+    interner: Arc<Interner>,
+    // Sorted by state group
+    entries: Vec<CompactEntry>,
+}
+
+impl CompactStateGroupMap {
+    /// Converts a map of state groups into the compact representation
+    pub fn from_map(map: &BTreeMap<i64, StateGroupEntry>) -> CompactStateGroupMap {
+        let mut interner = Interner::default();
+
+        let entries = map
+            .iter()
+            .map(|(sg, entry)| CompactEntry {
+                state_group: *sg,
+                in_range: entry.in_range,
+                prev_state_group: entry.prev_state_group,
+                state_map: interner.encode(&entry.state_map),
+            })
+            .collect();
+
+        CompactStateGroupMap {
+            interner: Arc::new(interner),
+            entries,
+        }
+    }
+
+    /// Converts a map of state groups that was derived from this one (e.g. by
+    /// compressing it) into the compact representation, using the same IDs
+    ///
+    /// Panics if the map contains any keys or values that aren't in this one
+    pub fn encode_derived(&self, map: &BTreeMap<i64, StateGroupEntry>) -> CompactStateGroupMap {
+        let mut new_map = self.empty_copy();
+
+        for (sg, entry) in map {
+            let rows = entry
+                .state_map
+                .iter()
+                .map(|((t, s), e)| {
+                    let key = self.interner.key_ids.get(&(Atom::from(t), Atom::from(s)));
+                    let value = self.interner.value_ids.get(e);
+                    match (key, value) {
+                        (Some(k), Some(v)) => (*k, *v),
+                        _ => panic!("State group {} has state not in the original map", sg),
+                    }
+                })
+                .collect();
+
+            new_map.add_group(
+                *sg,
+                entry.in_range,
+                entry.prev_state_group,
+                CompactStateMap::from_rows(rows),
+            );
+        }
+
+        new_map
+    }
+
+    /// Converts back into a map of `StateGroupEntry`s
+    pub fn to_map(&self) -> BTreeMap<i64, StateGroupEntry> {
+        self.entries
+            .iter()
+            .map(|entry| (entry.state_group, self.to_entry(entry.state_group)))
+            .collect()
+    }
+
+    /// Gets the state (or delta) of a group as a `StateMap`
+    pub fn decode(&self, state_map: &CompactStateMap) -> StateMap<Atom> {
+        self.interner.decode(state_map)
+    }
+
+    fn find(&self, state_group: i64) -> Option<&CompactEntry> {
+        self.entries
+            .binary_search_by_key(&state_group, |e| e.state_group)
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+
+    fn get(&self, state_group: i64) -> &CompactEntry {
+        self.find(state_group)
+            .unwrap_or_else(|| panic!("Missing {}", state_group))
+    }
+}
+
+impl StateGroupMap for CompactStateGroupMap {
+    type State = CompactStateMap;
+
+    fn empty_copy(&self) -> Self {
+        CompactStateGroupMap {
+            interner: self.interner.clone(),
+            entries: Vec::new(),
+        }
+    }
+
+    fn state_group_ids(&self) -> Box<dyn Iterator<Item = i64> + '_> {
+        Box::new(self.entries.iter().map(|e| e.state_group))
+    }
+
+    fn has_group(&self, state_group: i64) -> bool {
+        self.find(state_group).is_some()
+    }
+
+    fn group_in_range(&self, state_group: i64) -> bool {
+        self.get(state_group).in_range
+    }
+
+    fn group_prev(&self, state_group: i64) -> Option<i64> {
+        self.get(state_group).prev_state_group
+    }
+
+    fn group_delta(&self, state_group: i64) -> &CompactStateMap {
+        &self.get(state_group).state_map
+    }
+
+    /// Groups added in ascending order are pushed onto the end, others have to
+    /// be inserted in the middle (which means moving all the groups after them)
+    ///
+    /// Panics if `state_group` is already in the map
+    fn add_group(
+        &mut self,
+        state_group: i64,
+        in_range: bool,
+        prev_state_group: Option<i64>,
+        delta: CompactStateMap,
+    ) {
+        let entry = CompactEntry {
+            state_group,
+            in_range,
+            prev_state_group,
+            state_map: delta,
+        };
+
+        match self.entries.last() {
+            Some(last) if last.state_group >= state_group => {
+                match self
+                    .entries
+                    .binary_search_by_key(&state_group, |e| e.state_group)
+                {
+                    Ok(_) => panic!("State group {} is already in the map", state_group),
+                    Err(i) => self.entries.insert(i, entry),
+                }
+            }
+            _ => self.entries.push(entry),
+        }
+    }
+
+    fn group_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn to_entry(&self, state_group: i64) -> StateGroupEntry {
+        let entry = self.get(state_group);
+        StateGroupEntry {
+            in_range: entry.in_range,
+            prev_state_group: entry.prev_state_group,
+            state_map: self.decode(&entry.state_map),
+        }
+    }
+
+    fn state_len(state: &CompactStateMap) -> usize {
+        state.len()
+    }
+
+    fn apply_delta(state: &mut CompactStateMap, delta: &CompactStateMap) {
+        state.apply(delta)
+    }
+
+    fn calculate_delta(prev: &CompactStateMap, state: &CompactStateMap) -> Option<CompactStateMap> {
+        CompactStateMap::delta(prev, state)
+    }
+
+    fn state_hash(state: &CompactStateMap) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        state.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Collects the state groups loaded from the database (in any order) into a
+/// `CompactStateGroupMap`
+#[derive(Default)]
+pub struct CompactStateGroupMapBuilder {
+    interner: Interner,
+    groups: HashMap<i64, PendingGroup>,
+}
+
+#[derive(Default)]
+struct PendingGroup {
+    in_range: bool,
+    prev_state_group: Option<i64>,
+    rows: Vec<(u32, u32)>,
+}
+
+impl CompactStateGroupMapBuilder {
+    /// Sets the predecessor of a group, adding the group if it isn't there yet
+    pub fn set_prev(&mut self, state_group: i64, prev_state_group: Option<i64>) {
+        self.groups.entry(state_group).or_default().prev_state_group = prev_state_group;
+    }
+
+    /// Marks a group as being in range, adding the group if it isn't there yet
+    pub fn set_in_range(&mut self, state_group: i64) {
+        self.groups.entry(state_group).or_default().in_range = true;
+    }
+
+    /// Adds a row to the delta of a group, adding the group if it isn't there
+    /// yet
+    pub fn add_row(&mut self, state_group: i64, t: &str, s: &str, e: &str) {
+        let row = (
+            self.interner.intern_key(t, s),
+            self.interner.intern_value(e),
+        );
+        self.groups.entry(state_group).or_default().rows.push(row);
+    }
+
+    /// Returns the predecessors of the groups that haven't been added themselves
+    pub fn missing_predecessors(&self) -> Vec<i64> {
+        self.groups
+            .values()
+            .filter_map(|g| g.prev_state_group)
+            .filter(|prev| !self.groups.contains_key(prev))
+            .collect()
+    }
+
+    /// Sorts the groups into a `CompactStateGroupMap`
+    pub fn build(self) -> CompactStateGroupMap {
+        let mut groups: Vec<(i64, PendingGroup)> = self.groups.into_iter().collect();
+        groups.sort_unstable_by_key(|(sg, _)| *sg);
+
+        let entries = groups
+            .into_iter()
+            .map(|(state_group, group)| CompactEntry {
+                state_group,
+                in_range: group.in_range,
+                prev_state_group: group.prev_state_group,
+                state_map: CompactStateMap::from_rows(group.rows),
+            })
+            .collect();
+
+        CompactStateGroupMap {
+            interner: Arc::new(self.interner),
+            entries,
+        }
+    }
+}
+
+#[cfg(test)]
+mod compact_tests {
+    use std::collections::BTreeMap;
+
+    use state_map::StateMap;
+
+    use super::{CompactStateGroupMap, CompactStateGroupMapBuilder, CompactStateMap};
+    use crate::{collapse_state_maps, state_cache::StateCache, StateGroupEntry, StateGroupMap};
+
+    /// Builds the following structure
+    ///
+    /// 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+    ///
+    /// Each group i has state:
+    ///     ('node','is',      i)
+    ///     ('group',  j, 'seen') where j is less than or equal to i
+    fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+        let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+        let mut prev = None;
+
+        for i in 0i64..=13i64 {
+            let mut entry = StateGroupEntry {
+                in_range: i > 3,
+                prev_state_group: prev,
+                state_map: StateMap::new(),
+            };
+            entry
+                .state_map
+                .insert("group", &i.to_string(), "seen".into());
+            entry.state_map.insert("node", "is", i.to_string().into());
+
+            initial.insert(i, entry);
+
+            prev = Some(i)
+        }
+
+        initial
+    }
+
+    #[test]
+    fn from_map_and_to_map_round_trip() {
+        let initial = line_with_state();
+
+        let compact = CompactStateGroupMap::from_map(&initial);
+
+        assert_eq!(compact.group_count(), 14);
+        assert_eq!(compact.row_count(), 28);
+        assert_eq!(compact.to_map(), initial);
+    }
+
+    #[test]
+    fn collapsed_states_match() {
+        let initial = line_with_state();
+        let compact = CompactStateGroupMap::from_map(&initial);
+
+        let mut cache = StateCache::new(&compact);
+        for sg in 0i64..=13i64 {
+            assert_eq!(
+                compact.decode(&cache.get(sg)),
+                collapse_state_maps(&initial, sg)
+            );
+        }
+    }
+
+    #[test]
+    fn calculate_delta_works() {
+        let prev = CompactStateMap::from_rows(vec![(0, 0), (2, 2), (4, 4)]);
+        let state = CompactStateMap::from_rows(vec![(0, 0), (1, 1), (2, 3), (4, 4), (5, 5)]);
+
+        let delta = CompactStateGroupMap::calculate_delta(&prev, &state).unwrap();
+        assert_eq!(
+            delta,
+            CompactStateMap::from_rows(vec![(1, 1), (2, 3), (5, 5)])
+        );
+
+        // Applying the delta to prev should give back the state
+        let mut applied = prev.clone();
+        CompactStateGroupMap::apply_delta(&mut applied, &delta);
+        assert_eq!(applied, state);
+    }
+
+    #[test]
+    fn calculate_delta_fails_if_prev_has_extra_keys() {
+        let state = CompactStateMap::from_rows(vec![(1, 1), (2, 2)]);
+
+        // A key before, in between and after the keys in state
+        for extra in [0, 3, 5].iter() {
+            let prev = CompactStateMap::from_rows(vec![(*extra, 0), (2, 2)]);
+            assert_eq!(CompactStateGroupMap::calculate_delta(&prev, &state), None);
+        }
+    }
+
+    #[test]
+    fn from_rows_keeps_last_row_for_each_key() {
+        let state = CompactStateMap::from_rows(vec![(3, 1), (1, 1), (3, 2), (2, 2)]);
+
+        assert_eq!(
+            state.iter().collect::<Vec<_>>(),
+            vec![(1, 1), (2, 2), (3, 2)]
+        );
+    }
+
+    #[test]
+    fn encode_derived_shares_ids() {
+        let initial = line_with_state();
+        let compact = CompactStateGroupMap::from_map(&initial);
+
+        let mut derived = initial.clone();
+        derived.get_mut(&5).unwrap().prev_state_group = None;
+        derived.get_mut(&5).unwrap().state_map = collapse_state_maps(&initial, 5);
+
+        let compact_derived = compact.encode_derived(&derived);
+
+        assert_eq!(compact_derived.to_map(), derived);
+        assert_eq!(compact_derived.group_delta(4), compact.group_delta(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "State group 3 is already in the map")]
+    fn add_group_panics_if_already_present() {
+        let mut compact = CompactStateGroupMap::from_map(&line_with_state());
+        compact.add_group(3, true, None, CompactStateMap::default());
+    }
+
+    #[test]
+    fn add_group_keeps_groups_in_order() {
+        let initial = line_with_state();
+        let compact = CompactStateGroupMap::from_map(&initial);
+
+        let mut copy = compact.empty_copy();
+        for sg in [0, 1, 5, 2, 3, 4, 13, 6, 7, 8, 9, 10, 11, 12] {
+            copy.add_group(
+                sg,
+                compact.group_in_range(sg),
+                compact.group_prev(sg),
+                compact.group_delta(sg).clone(),
+            );
+        }
+
+        assert_eq!(
+            copy.state_group_ids().collect::<Vec<_>>(),
+            (0..=13).collect::<Vec<_>>()
+        );
+        assert_eq!(copy.to_map(), initial);
+    }
+
+    #[test]
+    fn builder_sorts_groups_and_dedups_rows() {
+        let mut builder = CompactStateGroupMapBuilder::default();
+
+        builder.set_prev(2, Some(1));
+        builder.set_in_range(2);
+        builder.add_row(2, "node", "is", "2");
+        builder.add_row(2, "node", "is", "2");
+        builder.set_prev(1, None);
+        builder.add_row(1, "node", "is", "1");
+
+        assert_eq!(builder.missing_predecessors(), Vec::<i64>::new());
+        builder.set_prev(3, Some(0));
+        assert_eq!(builder.missing_predecessors(), vec![0]);
+
+        let compact = builder.build();
+
+        let ids: Vec<i64> = compact.state_group_ids().collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert!(compact.group_in_range(2));
+        assert!(!compact.group_in_range(1));
+        assert_eq!(compact.group_delta(2).len(), 1);
+        assert_eq!(compact.group_prev(2), Some(1));
+    }
+}
@@ -30,10 +30,37 @@
 
 use indicatif::{ProgressBar, ProgressStyle};
 use state_map::StateMap;
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
 use string_cache::DefaultAtom as Atom;
 
-use super::{collapse_state_maps, StateGroupEntry};
+use super::{state_cache::StateCache, CompactStateGroupMap, StateGroupEntry, StateGroupMap};
+use backfill::BackfillDetector;
+use depth::depth_of;
+use snapshot::SnapshotTracker;
+
+mod backfill;
+mod cost;
+mod depth;
+mod duplicates;
+mod flatten;
+mod order;
+mod replay;
+mod snapshot;
+mod streaming;
+mod tuning;
+
+pub use cost::CostStrategy;
+pub use depth::{depth_histogram, query_cost, MaxDepthStrategy, QueryCost};
+pub use duplicates::{find_duplicates, DuplicateMode, DuplicatesStrategy};
+pub use flatten::{flatten, FlattenMode, FlattenStrategy};
+pub use order::GroupOrder;
+pub use replay::ReplayStrategy;
+pub use snapshot::SnapshotPolicy;
+pub use streaming::StreamingCompressor;
+pub use tuning::{default_candidates, evaluate, tune, TuningReport, TuningResult};
 
 /// Holds information about a particular level.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -110,35 +137,252 @@ impl Level {
 }
 
 /// Keeps track of some statistics of a compression run.
-#[derive(Default)]
+#[derive(Default, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct Stats {
     /// How many state groups we couldn't find a delta for, despite trying.
     pub resets_no_suitable_prev: usize,
     /// The sum of the rows of the state groups counted by
     /// `resets_no_suitable_prev`.
     pub resets_no_suitable_prev_size: usize,
+    /// How many state groups would have been reset if another group hadn't
+    /// been found to use as the base for their delta.
+    pub resets_avoided: usize,
+    /// How many state groups were given a different predecessor to keep their
+    /// chains within the maximum depth (see `MaxDepthStrategy`).
+    pub depth_limit_rebases: usize,
+    /// How many state groups looked like they were backfilled, and so were
+    /// left as they were (see `backfill`).
+    pub backfill_groups: usize,
+    /// How many state groups were pinned, and so were left as they were.
+    pub pinned_groups: usize,
+    /// How many state groups were made to store their full state by the
+    /// snapshot policy (see `SnapshotPolicy`).
+    pub snapshots: usize,
+    /// How many state groups in range had the same state as a group with a
+    /// lower ID (see `DuplicatesStrategy`).
+    pub duplicate_groups: usize,
     /// How many state groups we have changed.
     pub state_groups_changed: usize,
 }
 
+/// A method of building a new tree of deltas for a set of state groups.
+///
+/// Implementations are given the state groups loaded from the database and
+/// return the new map along with statistics about the changes made. Only the
+/// entries with `in_range` set may be changed, the others must be copied across
+/// unaltered. The loading, verification and SQL generation steps in `lib.rs`
+/// work with any implementation.
+pub trait CompressionStrategy {
+    /// Builds the new state group map from the original one
+    fn compress(
+        &mut self,
+        original_state_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> (BTreeMap<i64, StateGroupEntry>, Stats);
+
+    /// The same as `compress` but for state groups held in the compact
+    /// representation (see `--compact`)
+    ///
+    /// By default this converts to and from a `BTreeMap` so that `compress` can
+    /// be used, which loses the memory savings. Strategies that can work on
+    /// any `StateGroupMap` should override it.
+    fn compress_compact(
+        &mut self,
+        original_state_map: &CompactStateGroupMap,
+    ) -> (CompactStateGroupMap, Stats) {
+        let (new_state_group_map, stats) = self.compress(&original_state_map.to_map());
+
+        (
+            original_state_map.encode_derived(&new_state_group_map),
+            stats,
+        )
+    }
+
+    /// Returns the state of the levels as it was at the end of the last call
+    /// to `compress` (or as it was given if `compress` hasn't been called yet)
+    ///
+    /// This is what is saved so that the compressor can be continued later
+    fn get_level_info(&self) -> Vec<Level>;
+}
+
+/// The options that change how a `Compressor` (or a strategy built on the
+/// levels) works through the state groups. The defaults compress every group
+/// in order of ID, without snapshots
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CompressorOptions {
+    /// The order the state groups are placed into the levels in
+    pub order: GroupOrder,
+    /// When groups should store their full state regardless of the levels
+    pub snapshot_policy: SnapshotPolicy,
+    /// The state groups that must be left exactly as they are. They are still
+    /// placed into the levels unless their chains are deeper than the levels
+    /// would have made them
+    pub pinned: BTreeSet<i64>,
+    /// Whether groups that look like they were backfilled should be left as
+    /// they are (see `backfill`). This is ignored with `GroupOrder::Dag`
+    pub skip_backfill: bool,
+}
+
+/// The default compression strategy. Each state group is attached to the head
+/// of the lowest level that isn't full (see the module documentation above)
+pub struct LevelStrategy {
+    levels: Vec<Level>,
+    options: CompressorOptions,
+}
+
+impl LevelStrategy {
+    /// Creates a strategy with empty levels of the given maximum sizes
+    pub fn new(level_sizes: &[usize]) -> LevelStrategy {
+        LevelStrategy {
+            levels: level_sizes.iter().map(|size| Level::new(*size)).collect(),
+            options: CompressorOptions::default(),
+        }
+    }
+
+    /// Creates a strategy from the level state saved by a previous run
+    pub fn from_save(level_info: &[Level]) -> LevelStrategy {
+        LevelStrategy {
+            levels: level_info.to_vec(),
+            options: CompressorOptions::default(),
+        }
+    }
+
+    /// Sets all of the options at once, replacing any set before
+    pub fn with_options(mut self, options: CompressorOptions) -> LevelStrategy {
+        self.options = options;
+        self
+    }
+
+    /// Sets the order the state groups are placed into the levels in
+    pub fn with_order(mut self, order: GroupOrder) -> LevelStrategy {
+        self.options.order = order;
+        self
+    }
+
+    /// Sets when groups should store their full state regardless of the levels
+    pub fn with_snapshot_policy(mut self, snapshot_policy: SnapshotPolicy) -> LevelStrategy {
+        self.options.snapshot_policy = snapshot_policy;
+        self
+    }
+
+    /// Sets the state groups that must be left exactly as they are (they are
+    /// only placed into the levels if their chains are no deeper than the
+    /// levels would have made them)
+    pub fn with_pinned(mut self, pinned: BTreeSet<i64>) -> LevelStrategy {
+        self.options.pinned = pinned;
+        self
+    }
+
+    /// Sets whether groups that look like they were backfilled should be left
+    /// as they are (see `backfill`)
+    pub fn with_skip_backfill(mut self, skip_backfill: bool) -> LevelStrategy {
+        self.options.skip_backfill = skip_backfill;
+        self
+    }
+}
+
+impl CompressionStrategy for LevelStrategy {
+    fn compress(
+        &mut self,
+        original_state_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> (BTreeMap<i64, StateGroupEntry>, Stats) {
+        let compressor =
+            Compressor::compress_with_options(original_state_map, &self.levels, &self.options);
+        self.levels = compressor.get_level_info();
+
+        (compressor.new_state_group_map, compressor.stats)
+    }
+
+    fn compress_compact(
+        &mut self,
+        original_state_map: &CompactStateGroupMap,
+    ) -> (CompactStateGroupMap, Stats) {
+        let compressor =
+            Compressor::compress_with_options(original_state_map, &self.levels, &self.options);
+        self.levels = compressor.get_level_info();
+
+        (compressor.new_state_group_map, compressor.stats)
+    }
+
+    fn get_level_info(&self) -> Vec<Level> {
+        self.levels.clone()
+    }
+}
+
+/// The compression strategies built into this library that can be chosen by
+/// name (e.g. using the `--strategy` command line option)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// Uses `LevelStrategy`
+    #[default]
+    Level,
+    /// Uses `CostStrategy`
+    Cost,
+}
+
+impl FromStr for Strategy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "level" => Ok(Strategy::Level),
+            "cost" => Ok(Strategy::Cost),
+            _ => Err("Unknown compression strategy"),
+        }
+    }
+}
+
+impl Strategy {
+    /// Creates the chosen strategy, starting from the given level state and
+    /// working through the state groups as the options say
+    pub fn build(
+        self,
+        level_info: &[Level],
+        options: &CompressorOptions,
+    ) -> Box<dyn CompressionStrategy> {
+        match self {
+            Strategy::Level => {
+                Box::new(LevelStrategy::from_save(level_info).with_options(options.clone()))
+            }
+            Strategy::Cost => {
+                Box::new(CostStrategy::from_save(level_info).with_options(options.clone()))
+            }
+        }
+    }
+}
+
 /// Attempts to compress a set of state deltas using the given level sizes.
-pub struct Compressor<'a> {
-    original_state_map: &'a BTreeMap<i64, StateGroupEntry>,
-    pub new_state_group_map: BTreeMap<i64, StateGroupEntry>,
+///
+/// This works with any representation of the state groups (see
+/// `StateGroupMap`), and produces a new map of the same kind.
+pub struct Compressor<'a, M: StateGroupMap = BTreeMap<i64, StateGroupEntry>> {
+    original_state_map: &'a M,
+    state_cache: StateCache<'a, M>,
+    pub new_state_group_map: M,
     levels: Vec<Level>,
+    order: GroupOrder,
+    snapshots: SnapshotTracker,
+    pinned: BTreeSet<i64>,
+    backfill: BackfillDetector,
     pub stats: Stats,
 }
 
-impl<'a> Compressor<'a> {
+impl<'a, M: StateGroupMap> Compressor<'a, M> {
     /// Creates a compressor and runs the compression algorithm.
-    pub fn compress(
-        original_state_map: &'a BTreeMap<i64, StateGroupEntry>,
-        level_sizes: &[usize],
-    ) -> Compressor<'a> {
+    ///
+    /// (Outside of the tests, compressors are created through `LevelStrategy`)
+    #[cfg(test)]
+    pub fn compress(original_state_map: &'a M, level_sizes: &[usize]) -> Compressor<'a, M> {
         let mut compressor = Compressor {
             original_state_map,
-            new_state_group_map: BTreeMap::new(),
+            state_cache: StateCache::new(original_state_map),
+            new_state_group_map: original_state_map.empty_copy(),
             levels: level_sizes.iter().map(|size| Level::new(*size)).collect(),
+            order: GroupOrder::Id,
+            snapshots: SnapshotTracker::default(),
+            pinned: BTreeSet::new(),
+            backfill: BackfillDetector::new(false, GroupOrder::Id),
             stats: Stats::default(),
         };
 
@@ -151,9 +395,23 @@ impl<'a> Compressor<'a> {
     /// used when restoring compressor state from a previous run
     /// in which case the levels heads are also known
     pub fn compress_from_save(
-        original_state_map: &'a BTreeMap<i64, StateGroupEntry>,
+        original_state_map: &'a M,
         level_info: &[Level],
-    ) -> Compressor<'a> {
+    ) -> Compressor<'a, M> {
+        Compressor::compress_with_options(
+            original_state_map,
+            level_info,
+            &CompressorOptions::default(),
+        )
+    }
+
+    /// The same as `compress_from_save` but working through the state groups
+    /// as the options say (see `CompressorOptions`)
+    pub fn compress_with_options(
+        original_state_map: &'a M,
+        level_info: &[Level],
+        options: &CompressorOptions,
+    ) -> Compressor<'a, M> {
         let levels = level_info
             .iter()
             .map(|l| Level::restore((*l).max_length, (*l).current_chain_length, (*l).head))
@@ -161,8 +419,13 @@ impl<'a> Compressor<'a> {
 
         let mut compressor = Compressor {
             original_state_map,
-            new_state_group_map: BTreeMap::new(),
+            state_cache: StateCache::new(original_state_map),
+            new_state_group_map: original_state_map.empty_copy(),
             levels,
+            order: options.order,
+            snapshots: SnapshotTracker::new(options.snapshot_policy),
+            pinned: options.pinned.clone(),
+            backfill: BackfillDetector::new(options.skip_backfill, options.order),
             stats: Stats::default(),
         };
 
@@ -177,7 +440,7 @@ impl<'a> Compressor<'a> {
 
     /// Actually runs the compression algorithm
     fn create_new_tree(&mut self) {
-        if !self.new_state_group_map.is_empty() {
+        if self.new_state_group_map.group_count() != 0 {
             panic!("Can only call `create_new_tree` once");
         }
 
@@ -185,7 +448,7 @@ impl<'a> Compressor<'a> {
         if cfg!(feature = "no-progress-bars") {
             pb = ProgressBar::hidden();
         } else {
-            pb = ProgressBar::new(self.original_state_map.len() as u64);
+            pb = ProgressBar::new(self.original_state_map.group_count() as u64);
         }
         pb.set_style(
             ProgressStyle::default_bar().template("[{elapsed_precise}] {bar} {pos}/{len} {msg}"),
@@ -193,50 +456,103 @@ impl<'a> Compressor<'a> {
         pb.set_message("state groups");
         pb.enable_steady_tick(100);
 
-        for (&state_group, entry) in self.original_state_map {
+        let original_state_map = self.original_state_map;
+
+        for state_group in self.order.iter(original_state_map) {
+            let original_prev = original_state_map.group_prev(state_group);
+            let original_delta = original_state_map.group_delta(state_group);
+
             // Check whether this entry is in_range or is just present in the map due to being
             // a predecessor of a group that IS in_range for compression
-            if !entry.in_range {
-                let new_entry = StateGroupEntry {
-                    // in_range is kept the same so that the new entry is equal to the old entry
-                    // otherwise it might trigger a useless database transaction
-                    in_range: entry.in_range,
-                    prev_state_group: entry.prev_state_group,
-                    state_map: entry.state_map.clone(),
-                };
-                // Paranoidly assert that not making changes to this entry
-                // could probably be removed...
-                assert!(new_entry == *entry);
-                self.new_state_group_map.insert(state_group, new_entry);
+            if !original_state_map.group_in_range(state_group) {
+                // in_range is kept the same so that the new entry is equal to the old entry
+                // otherwise it might trigger a useless database transaction
+                self.new_state_group_map.add_group(
+                    state_group,
+                    false,
+                    original_prev,
+                    original_delta.clone(),
+                );
+
+                continue;
+            }
+
+            if self.backfill.is_backfill(&self.levels, original_prev) {
+                self.backfill.add_backfilled(state_group);
+                self.stats.backfill_groups += 1;
+
+                self.new_state_group_map.add_group(
+                    state_group,
+                    true,
+                    original_prev,
+                    original_delta.clone(),
+                );
 
+                pb.inc(1);
                 continue;
             }
-            let mut prev_state_group = None;
-            for level in &mut self.levels {
-                if level.has_space() {
-                    prev_state_group = level.get_head();
-                    level.update(state_group, true);
-                    break;
-                } else {
-                    level.update(state_group, false);
+
+            // Pinned groups keep their original predecessor, so their chains
+            // can be any length. They are only placed into the levels (so that
+            // the groups after them can be based on them) if that doesn't make
+            // those groups' chains any deeper
+            if self.pinned.contains(&state_group) {
+                self.stats.pinned_groups += 1;
+
+                self.new_state_group_map.add_group(
+                    state_group,
+                    true,
+                    original_prev,
+                    original_delta.clone(),
+                );
+
+                if pinned_fits_levels(
+                    &self.levels,
+                    &self.new_state_group_map,
+                    &mut BTreeMap::new(),
+                    state_group,
+                ) {
+                    place_in_levels(&mut self.levels, state_group);
+                    self.backfill.add_placed(state_group);
                 }
+
+                pb.inc(1);
+                continue;
             }
 
-            let (delta, prev_state_group) = if entry.prev_state_group == prev_state_group {
-                (entry.state_map.clone(), prev_state_group)
+            let prev_state_group = place_in_levels(&mut self.levels, state_group);
+            self.backfill.add_placed(state_group);
+
+            let suggested_is_original = original_prev == prev_state_group;
+
+            let (mut delta, mut prev_state_group) = if suggested_is_original {
+                (original_delta.clone(), prev_state_group)
             } else {
-                self.stats.state_groups_changed += 1;
                 self.get_delta(prev_state_group, state_group)
             };
 
-            self.new_state_group_map.insert(
-                state_group,
-                StateGroupEntry {
-                    in_range: true,
-                    prev_state_group,
-                    state_map: delta,
-                },
-            );
+            if self.snapshots.needs_snapshot(
+                &self.new_state_group_map,
+                prev_state_group,
+                M::state_len(&delta),
+            ) {
+                self.stats.snapshots += 1;
+                snapshot::reset_levels(&mut self.levels, state_group);
+
+                delta = self.state_cache.get(state_group);
+                prev_state_group = None;
+            }
+
+            if original_prev != prev_state_group {
+                self.stats.state_groups_changed += 1;
+            } else if !suggested_is_original {
+                // We've ended up back at the original predecessor, so there
+                // is no need to change this group after all
+                delta = original_delta.clone();
+            }
+
+            self.new_state_group_map
+                .add_group(state_group, true, prev_state_group, delta);
 
             pb.inc(1);
         }
@@ -249,11 +565,13 @@ impl<'a> Compressor<'a> {
     /// This is not always possible if the given candidate previous state group
     /// have state keys that are not in the new state group. In this case the
     /// function will try and iterate back up the current tree to find a state
-    /// group that can be used as a base for a delta.
+    /// group that can be used as a base for a delta. If that fails then the
+    /// other candidates from `find_alternative_base` are tried before falling
+    /// back to storing the full state.
     ///
     /// Returns the state map and the actual base state group (if any) used.
-    fn get_delta(&mut self, prev_sg: Option<i64>, sg: i64) -> (StateMap<Atom>, Option<i64>) {
-        let state_map = collapse_state_maps(self.original_state_map, sg);
+    fn get_delta(&mut self, prev_sg: Option<i64>, sg: i64) -> (M::State, Option<i64>) {
+        let state_map = self.state_cache.get(sg);
 
         let mut prev_sg = if let Some(prev_sg) = prev_sg {
             prev_sg
@@ -261,44 +579,178 @@ impl<'a> Compressor<'a> {
             return (state_map, None);
         };
 
+        // The groups that have been found not to be a valid base
+        let mut tried = Vec::new();
+
+        let suggested_prev = prev_sg;
+
         // This is a loop to go through to find the first prev_sg which can be
         // a valid base for the state group.
-        let mut prev_state_map;
-        'outer: loop {
-            prev_state_map = collapse_state_maps(self.original_state_map, prev_sg);
-            for (t, s) in prev_state_map.keys() {
-                if !state_map.contains_key(t, s) {
-                    // This is not a valid base as it contains key the new state
-                    // group doesn't have. Attempt to walk up the tree to find a
-                    // better base.
-                    if let Some(psg) = self.new_state_group_map[&prev_sg].prev_state_group {
-                        prev_sg = psg;
-                        continue 'outer;
-                    }
-
-                    // Couldn't find a new base, so we give up and just persist
-                    // a full state group here.
-                    self.stats.resets_no_suitable_prev += 1;
-                    self.stats.resets_no_suitable_prev_size += state_map.len();
-
-                    return (state_map, None);
-                }
+        loop {
+            let prev_state_map = self.state_cache.get(prev_sg);
+            if let Some(delta_map) = M::calculate_delta(&prev_state_map, &state_map) {
+                // We've found a valid base
+                return (delta_map, Some(prev_sg));
+            }
+            tried.push(prev_sg);
+
+            // This is not a valid base as it contains key the new state
+            // group doesn't have. Attempt to walk up the tree to find a
+            // better base.
+            if let Some(psg) = self.new_state_group_map.group_prev(prev_sg) {
+                prev_sg = psg;
+                continue;
             }
 
             break;
         }
 
-        // We've found a valid base, now we just need to calculate the delta.
-        let mut delta_map = StateMap::new();
+        // Walking up the tree didn't work, so try looking elsewhere
+        if let Some((delta_map, base_sg)) =
+            self.find_alternative_base(sg, &state_map, suggested_prev, &tried)
+        {
+            self.stats.resets_avoided += 1;
+            return (delta_map, Some(base_sg));
+        }
+
+        // Couldn't find a new base, so we give up and just persist
+        // a full state group here.
+        self.stats.resets_no_suitable_prev += 1;
+        self.stats.resets_no_suitable_prev_size += M::state_len(&state_map);
+
+        (state_map, None)
+    }
+
+    /// Looks for a group to use as the base for the delta of `sg` when none of
+    /// the ancestors of the suggested predecessor can be used. The candidates
+    /// are:
+    ///
+    /// - the heads of the levels
+    /// - the original predecessor of `sg` (this is always a valid base if it
+    ///   exists, as deltas can only add to the state of their predecessor)
+    ///
+    /// Only candidates whose chain is no deeper than that of the suggested
+    /// predecessor are used, so that `sg` doesn't end up on a longer chain
+    /// than the levels would have put it on.
+    ///
+    /// Returns the smallest delta found along with the group it is based on
+    ///
+    /// # Arguments
+    ///
+    /// * `sg`              -   The state group to find a base for
+    /// * `state_map`       -   The full state of `sg`
+    /// * `suggested_prev`  -   The predecessor the levels suggested for `sg`
+    /// * `tried`           -   The groups already found not to be valid bases
+    fn find_alternative_base(
+        &mut self,
+        sg: i64,
+        state_map: &M::State,
+        suggested_prev: i64,
+        tried: &[i64],
+    ) -> Option<(M::State, i64)> {
+        let mut candidates: Vec<i64> = self.levels.iter().filter_map(|l| l.get_head()).collect();
+        candidates.extend(self.original_state_map.group_prev(sg));
+
+        let mut depths = BTreeMap::new();
+        let max_depth = depth_of(&self.new_state_group_map, &mut depths, suggested_prev);
+
+        let mut best: Option<(M::State, i64)> = None;
+
+        for candidate in candidates {
+            // Only groups that are already in the new map can be used (which
+            // also stops `sg` being chosen as its own predecessor)
+            if tried.contains(&candidate) || !self.new_state_group_map.has_group(candidate) {
+                continue;
+            }
+
+            if depth_of(&self.new_state_group_map, &mut depths, candidate) > max_depth {
+                continue;
+            }
 
-        for ((t, s), e) in state_map.iter() {
-            if prev_state_map.get(t, s) != Some(e) {
-                delta_map.insert(t, s, e.clone());
+            let prev_state_map = self.state_cache.get(candidate);
+            if let Some(delta_map) = M::calculate_delta(&prev_state_map, state_map) {
+                let is_smaller = match &best {
+                    Some((best_delta, _)) => M::state_len(&delta_map) < M::state_len(best_delta),
+                    None => true,
+                };
+                if is_smaller {
+                    best = Some((delta_map, candidate));
+                }
             }
         }
 
-        (delta_map, Some(prev_sg))
+        best
+    }
+}
+
+/// Adds a state group to the lowest level that isn't full, resetting the levels
+/// below it to start new chains at that state group
+///
+/// Returns the group the levels suggest should be the new predecessor (if any)
+fn place_in_levels(levels: &mut [Level], state_group: i64) -> Option<i64> {
+    for level in levels {
+        if level.has_space() {
+            let prev_state_group = level.get_head();
+            level.update(state_group, true);
+            return prev_state_group;
+        } else {
+            level.update(state_group, false);
+        }
+    }
+
+    None
+}
+
+/// Returns the group that `place_in_levels` would suggest as the predecessor of
+/// the next state group, without changing the levels
+fn next_in_levels(levels: &[Level]) -> Option<i64> {
+    levels
+        .iter()
+        .find(|level| level.has_space())
+        .and_then(|level| level.get_head())
+}
+
+/// Whether a pinned state group (already added to the new map with its
+/// original predecessor) can be placed into the levels. This is only the case
+/// if its chain is no deeper than the levels would have made it, so that the
+/// groups based on it don't end up on longer chains than usual
+fn pinned_fits_levels<M: StateGroupMap>(
+    levels: &[Level],
+    new_state_group_map: &M,
+    depths: &mut BTreeMap<i64, usize>,
+    state_group: i64,
+) -> bool {
+    let level_depth = match next_in_levels(levels) {
+        Some(prev) => depth_of(new_state_group_map, depths, prev) + 1,
+        None => 1,
+    };
+
+    depth_of(new_state_group_map, depths, state_group) <= level_depth
+}
+
+/// Calculates the delta that turns `prev_state_map` into `state_map`
+///
+/// Returns None if `prev_state_map` has state keys that `state_map` doesn't, as
+/// a delta can only add to (or replace entries in) the state of its predecessor
+pub(crate) fn calculate_delta(
+    prev_state_map: &StateMap<Atom>,
+    state_map: &StateMap<Atom>,
+) -> Option<StateMap<Atom>> {
+    for (t, s) in prev_state_map.keys() {
+        if !state_map.contains_key(t, s) {
+            return None;
+        }
+    }
+
+    let mut delta_map = StateMap::new();
+
+    for ((t, s), e) in state_map.iter() {
+        if prev_state_map.get(t, s) != Some(e) {
+            delta_map.insert(t, s, e.clone());
+        }
     }
+
+    Some(delta_map)
 }
 
 #[cfg(test)]
@@ -309,3 +761,39 @@ mod compressor_tests;
 
 #[cfg(test)]
 mod stats_tests;
+
+#[cfg(test)]
+mod strategy_tests;
+
+#[cfg(test)]
+mod cost_tests;
+
+#[cfg(test)]
+mod depth_tests;
+
+#[cfg(test)]
+mod streaming_tests;
+
+#[cfg(test)]
+mod tuning_tests;
+
+#[cfg(test)]
+mod order_tests;
+
+#[cfg(test)]
+mod backfill_tests;
+
+#[cfg(test)]
+mod snapshot_tests;
+
+#[cfg(test)]
+mod flatten_tests;
+
+#[cfg(test)]
+mod pinned_tests;
+
+#[cfg(test)]
+mod duplicates_tests;
+
+#[cfg(test)]
+mod replay_tests;
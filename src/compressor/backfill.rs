@@ -0,0 +1,109 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Spotting state groups that were created by backfill.
+//!
+//! When Synapse backfills old history into a room, the new state groups get
+//! new (high) IDs but are based on state groups from far back in the room's
+//! history. Placing them into the levels means taking a delta between their
+//! old state and the current state of the room, which is usually huge. Enough
+//! of them in a chunk can make the whole chunk come out bigger than it started,
+//! in which case nothing in it gets compressed at all.
+//!
+//! Instead these groups are spotted and left exactly as they are (they aren't
+//! placed into the levels either, so the groups around them are unaffected). A
+//! group is treated as backfilled if its original predecessor:
+//!
+//! - was itself treated as backfilled, or
+//! - is older than all of the current level heads, and isn't one of the last
+//!   `RECENT_GROUPS` groups to be placed into the levels (so that groups
+//!   based on a slightly older group, e.g. due to a fork, aren't caught).
+//!
+//! This is only a guess (a fork that lives for longer than `RECENT_GROUPS`
+//! groups looks the same), so it is only done when asked for. It is never done
+//! with `GroupOrder::Dag`, as the first group of every branch after the first
+//! is based on a group from before the current level heads in that order.
+
+use std::collections::{BTreeSet, VecDeque};
+
+use super::{GroupOrder, Level};
+
+/// How many of the groups most recently placed into the levels are always
+/// considered close enough to be used as an original predecessor
+pub(super) const RECENT_GROUPS: usize = 100;
+
+/// Keeps track of what is needed to spot backfilled state groups (see the
+/// module documentation)
+pub(super) struct BackfillDetector {
+    // Whether or not groups are checked at all
+    enabled: bool,
+    // The groups most recently placed into the levels, oldest first
+    recent: VecDeque<i64>,
+    // The groups that have been treated as backfilled
+    backfilled: BTreeSet<i64>,
+}
+
+impl BackfillDetector {
+    /// Creates a detector for state groups placed into the levels in the given
+    /// order. Unless `skip_backfill` is set (and the order isn't
+    /// `GroupOrder::Dag`) no group is ever treated as backfilled
+    pub fn new(skip_backfill: bool, order: GroupOrder) -> BackfillDetector {
+        BackfillDetector {
+            enabled: skip_backfill && order != GroupOrder::Dag,
+            recent: VecDeque::new(),
+            backfilled: BTreeSet::new(),
+        }
+    }
+
+    /// Whether a group with the given original predecessor looks like it was
+    /// backfilled
+    pub fn is_backfill(&self, levels: &[Level], original_prev: Option<i64>) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        let prev = match original_prev {
+            Some(prev) => prev,
+            None => return false,
+        };
+
+        if self.backfilled.contains(&prev) {
+            return true;
+        }
+
+        let oldest_head = match levels.iter().filter_map(|l| l.get_head()).min() {
+            Some(head) => head,
+            None => return false,
+        };
+
+        prev < oldest_head && !self.recent.contains(&prev)
+    }
+
+    /// Records that a group was treated as backfilled
+    pub fn add_backfilled(&mut self, state_group: i64) {
+        self.backfilled.insert(state_group);
+    }
+
+    /// Records that a group was placed into the levels
+    pub fn add_placed(&mut self, state_group: i64) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.recent.len() == RECENT_GROUPS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(state_group);
+    }
+}
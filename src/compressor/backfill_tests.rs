@@ -0,0 +1,183 @@
+use crate::{
+    check_that_maps_match, collapse_state_maps,
+    compressor::{
+        backfill::RECENT_GROUPS, CompressionStrategy, CostStrategy, GroupOrder, LevelStrategy,
+        StreamingCompressor,
+    },
+    StateGroupEntry,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+
+/// The last group of the line built by `line_with_branch`
+const LINE_END: i64 = RECENT_GROUPS as i64 + 50;
+
+/// Builds the following structure
+///
+/// 0-1-2-3-...-LINE_END-(LINE_END+3)
+///       \-(LINE_END+1)-(LINE_END+2)
+///
+/// where the branch comes off `branch_from` (2 in the picture)
+///
+/// Each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') for each j on the path to i
+fn line_with_branch(branch_from: i64) -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    let mut add = |i: i64, prev: Option<i64>| {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+        initial.insert(i, entry);
+    };
+
+    add(0, None);
+    for i in 1..=LINE_END {
+        add(i, Some(i - 1));
+    }
+    add(LINE_END + 1, Some(branch_from));
+    add(LINE_END + 2, Some(LINE_END + 1));
+    add(LINE_END + 3, Some(LINE_END));
+
+    initial
+}
+
+#[test]
+fn level_strategy_leaves_backfilled_groups_alone() {
+    let initial = line_with_branch(2);
+
+    let (new_state_group_map, stats) = LevelStrategy::new(&[3, 3])
+        .with_skip_backfill(true)
+        .compress(&initial);
+
+    // The branch is based on a group from long before any of the level heads,
+    // and the group after it is based on the branch
+    assert_eq!(stats.backfill_groups, 2);
+    assert_eq!(
+        new_state_group_map[&(LINE_END + 1)],
+        initial[&(LINE_END + 1)]
+    );
+    assert_eq!(
+        new_state_group_map[&(LINE_END + 2)],
+        initial[&(LINE_END + 2)]
+    );
+
+    // The line carries on as if the branch wasn't there
+    let line: BTreeMap<i64, StateGroupEntry> = initial
+        .iter()
+        .filter(|(sg, _)| **sg <= LINE_END || **sg == LINE_END + 3)
+        .map(|(sg, entry)| (*sg, entry.clone()))
+        .collect();
+    let (new_line, _) = LevelStrategy::new(&[3, 3]).compress(&line);
+    assert_eq!(
+        new_state_group_map[&(LINE_END + 3)],
+        new_line[&(LINE_END + 3)]
+    );
+
+    check_that_maps_match(&initial, &new_state_group_map);
+}
+
+#[test]
+fn recent_predecessors_are_not_backfill() {
+    // A branch off a group that was placed into the levels recently (e.g. a
+    // fork) is compressed as normal
+    let initial = line_with_branch(LINE_END - 5);
+
+    let (new_state_group_map, stats) = LevelStrategy::new(&[3, 3])
+        .with_skip_backfill(true)
+        .compress(&initial);
+
+    assert_eq!(stats.backfill_groups, 0);
+    check_that_maps_match(&initial, &new_state_group_map);
+}
+
+#[test]
+fn cost_strategy_leaves_backfilled_groups_alone() {
+    let initial = line_with_branch(2);
+
+    let (new_state_group_map, stats) = CostStrategy::new(&[3, 3])
+        .with_skip_backfill(true)
+        .compress(&initial);
+
+    assert_eq!(stats.backfill_groups, 2);
+    assert_eq!(
+        new_state_group_map[&(LINE_END + 1)],
+        initial[&(LINE_END + 1)]
+    );
+    assert_eq!(
+        new_state_group_map[&(LINE_END + 2)],
+        initial[&(LINE_END + 2)]
+    );
+
+    check_that_maps_match(&initial, &new_state_group_map);
+}
+
+#[test]
+fn streaming_leaves_backfilled_groups_alone() {
+    let initial = line_with_branch(2);
+
+    let (level_map, _) = LevelStrategy::new(&[3, 3])
+        .with_skip_backfill(true)
+        .compress(&initial);
+
+    let mut streaming = StreamingCompressor::new(&[3, 3]).with_skip_backfill(true);
+    let mut new_state_group_map = BTreeMap::new();
+    for (sg, entry) in &initial {
+        let new_entry = streaming.add_group(
+            *sg,
+            entry.prev_state_group,
+            entry.state_map.clone(),
+            |prev| collapse_state_maps(&initial, prev),
+        );
+        new_state_group_map.insert(*sg, new_entry);
+    }
+
+    assert_eq!(streaming.stats.backfill_groups, 2);
+    assert_eq!(new_state_group_map, level_map);
+}
+
+#[test]
+fn backfill_is_only_looked_for_if_asked() {
+    let initial = line_with_branch(2);
+
+    let (new_state_group_map, stats) = LevelStrategy::new(&[3, 3]).compress(&initial);
+
+    assert_eq!(stats.backfill_groups, 0);
+    check_that_maps_match(&initial, &new_state_group_map);
+}
+
+#[test]
+fn forks_are_not_backfill_in_dag_order() {
+    // In dag order the branch is placed into the levels after the whole of the
+    // line, so it is based on a group from long before the level heads. It is
+    // still compressed as normal
+    let initial = line_with_branch(2);
+
+    let (new_state_group_map, stats) = LevelStrategy::new(&[3, 3])
+        .with_order(GroupOrder::Dag)
+        .with_skip_backfill(true)
+        .compress(&initial);
+
+    let (dag_map, _) = LevelStrategy::new(&[3, 3])
+        .with_order(GroupOrder::Dag)
+        .compress(&initial);
+
+    assert_eq!(stats.backfill_groups, 0);
+    assert_eq!(new_state_group_map, dag_map);
+    check_that_maps_match(&initial, &new_state_group_map);
+
+    let (new_state_group_map, stats) = CostStrategy::new(&[3, 3])
+        .with_order(GroupOrder::Dag)
+        .with_skip_backfill(true)
+        .compress(&initial);
+
+    assert_eq!(stats.backfill_groups, 0);
+    check_that_maps_match(&initial, &new_state_group_map);
+}
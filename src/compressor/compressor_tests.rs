@@ -1,9 +1,14 @@
 use crate::{
-    compressor::{Compressor, Level, Stats},
+    check_that_maps_match, collapse_state_maps,
+    compressor::{
+        backfill::BackfillDetector, depth::depth_of, snapshot::SnapshotTracker, Compressor,
+        GroupOrder, Level, Stats,
+    },
+    state_cache::StateCache,
     StateGroupEntry,
 };
 use state_map::StateMap;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use string_cache::DefaultAtom as Atom;
 
 #[test]
@@ -115,8 +120,13 @@ fn create_new_tree_does_nothing_if_already_compressed() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
         stats: Stats::default(),
     };
 
@@ -150,8 +160,13 @@ fn create_new_tree_respects_levels() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
         stats: Stats::default(),
     };
     compressor.create_new_tree();
@@ -212,8 +227,13 @@ fn create_new_tree_panics_if_run_twice() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
         stats: Stats::default(),
     };
     compressor.create_new_tree();
@@ -243,8 +263,13 @@ fn create_new_tree_respects_all_not_in_range() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
         stats: Stats::default(),
     };
     compressor.create_new_tree();
@@ -305,8 +330,13 @@ fn create_new_tree_respects_some_not_in_range() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
         stats: Stats::default(),
     };
     compressor.create_new_tree();
@@ -388,8 +418,13 @@ fn create_new_tree_deals_with_impossible_preds() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
         stats: Stats::default(),
     };
     compressor.create_new_tree();
@@ -403,7 +438,9 @@ fn create_new_tree_deals_with_impossible_preds() {
     // This should create the following structure
     //
     // Brackets mean that has NO predecessor but is in that position in the
-    // levels tree
+    // levels tree. The levels suggest 3 as the predecessor for 6 but that
+    // isn't possible, and its original predecessor 5 is deeper than 3, so 6
+    // stores its full state
     //
     // 0  3\        12
     // 1 (4)(6)\    13
@@ -633,7 +670,7 @@ fn get_delta_returns_snapshot_if_no_prev_possible() {
     // levels tree
     //
     // 0  3\        12
-    // 1 (4)(6)\    13
+    // 1 (4) 6\     13
     // 2  5  7  9
     //       8  10
     //          11
@@ -667,8 +704,13 @@ fn get_delta_returns_snapshot_if_no_prev_possible() {
     // build the compressor with this partialy built new map
     let mut compressor = Compressor {
         original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
         new_state_group_map: new_map,
         levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
         stats: Stats::default(),
     };
 
@@ -694,3 +736,275 @@ fn get_delta_returns_snapshot_if_no_prev_possible() {
     assert_eq!(found_delta, expected_delta);
     assert_eq!(found_pred, None);
 }
+
+#[test]
+fn get_delta_falls_back_to_original_predecessor() {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    // This starts with the following structure
+    //
+    // (note missing 3-4 link)
+    // 0-1-2-3
+    // 4-5-6
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') where j is ancestor of i
+    for i in 0i64..=6i64 {
+        if i == 4 {
+            prev = None
+        }
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    // build up new_tree after 0,1,2,3,4,5 added, with 3 left on 2
+    //
+    // 0
+    // 1
+    // 2 (4)
+    // 3  5
+    let mut new_map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    for i in 0..=5 {
+        new_map.insert(i, initial[&i].clone());
+    }
+
+    let mut compressor = Compressor {
+        original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
+        new_state_group_map: new_map,
+        levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
+        stats: Stats::default(),
+    };
+
+    // make the levels how they would be after 6 is added
+    let mut levels_iter = compressor.levels.iter_mut();
+
+    let l1 = levels_iter.next().unwrap();
+    l1.head = Some(6);
+    l1.current_chain_length = 1;
+
+    let l2 = levels_iter.next().unwrap();
+    l2.head = Some(6);
+    l2.current_chain_length = 2;
+
+    // None of 3 and its predecessors can be used as a base for 6, but 6's
+    // original predecessor can be (and its chain isn't as deep as 3's)
+    let (found_delta, found_pred) = compressor.get_delta(Some(3), 6);
+
+    let mut expected_delta: StateMap<Atom> = StateMap::new();
+    expected_delta.insert("node", "is", "6".into());
+    expected_delta.insert("group", "6", "seen".into());
+
+    assert_eq!(found_delta, expected_delta);
+    assert_eq!(found_pred, Some(5));
+    assert_eq!(compressor.stats.resets_avoided, 1);
+    assert_eq!(compressor.stats.resets_no_suitable_prev, 0);
+}
+
+#[test]
+fn get_delta_only_falls_back_to_bases_no_deeper_than_suggested() {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    // This starts with the following structure
+    //
+    // (note missing 3-4 link)
+    // 0-1-2-3
+    // 4-5-6
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') where j is ancestor of i
+    for i in 0i64..=6i64 {
+        if i == 4 {
+            prev = None
+        }
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    // build up new_tree after 0,1,2,3,4,5 added
+    //
+    // 0  3
+    // 1 (4)
+    // 2  5
+    let mut new_map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    for i in [0, 1, 2, 4, 5].iter() {
+        new_map.insert(*i, initial[i].clone());
+    }
+
+    let mut entry_3: StateMap<Atom> = StateMap::new();
+    entry_3.insert("node", "is", "3".into());
+    entry_3.insert("group", "0", "seen".into());
+    entry_3.insert("group", "1", "seen".into());
+    entry_3.insert("group", "2", "seen".into());
+    entry_3.insert("group", "3", "seen".into());
+    new_map.insert(
+        3,
+        StateGroupEntry {
+            in_range: true,
+            prev_state_group: None,
+            state_map: entry_3,
+        },
+    );
+
+    let mut compressor = Compressor {
+        original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
+        new_state_group_map: new_map,
+        levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
+        stats: Stats::default(),
+    };
+
+    // make the levels how they would be after 6 is added
+    let mut levels_iter = compressor.levels.iter_mut();
+
+    let l1 = levels_iter.next().unwrap();
+    l1.head = Some(6);
+    l1.current_chain_length = 1;
+
+    let l2 = levels_iter.next().unwrap();
+    l2.head = Some(6);
+    l2.current_chain_length = 2;
+
+    // 3 can't be used as a base for 6 (and has no predecessor to try), and
+    // 6's original predecessor is on a deeper chain than 3
+    let (found_delta, found_pred) = compressor.get_delta(Some(3), 6);
+
+    assert_eq!(found_delta, collapse_state_maps(&initial, 6));
+    assert_eq!(found_pred, None);
+    assert_eq!(compressor.stats.resets_avoided, 0);
+    assert_eq!(compressor.stats.resets_no_suitable_prev, 1);
+}
+
+#[test]
+fn get_delta_falls_back_to_level_head() {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    // This starts with three unconnected groups
+    //
+    // 0 1 2
+    //
+    // Where 0 has state ('a','',0), 1 has state ('b','',1) and 2 has state
+    // ('a','',0) and ('c','',2)
+    let groups: [(i64, &[&str]); 3] = [(0, &["a"]), (1, &["b"]), (2, &["a", "c"])];
+    for (sg, keys) in groups.iter() {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: None,
+            state_map: StateMap::new(),
+        };
+        for key in keys.iter() {
+            let value = if *key == "a" { "0" } else { "" };
+            entry.state_map.insert(key, "", value.into());
+        }
+        initial.insert(*sg, entry);
+    }
+
+    let mut new_map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    new_map.insert(0, initial[&0].clone());
+    new_map.insert(1, initial[&1].clone());
+
+    let mut compressor = Compressor {
+        original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
+        new_state_group_map: new_map,
+        levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
+        stats: Stats::default(),
+    };
+
+    let mut levels_iter = compressor.levels.iter_mut();
+
+    let l1 = levels_iter.next().unwrap();
+    l1.head = Some(1);
+    l1.current_chain_length = 1;
+
+    let l2 = levels_iter.next().unwrap();
+    l2.head = Some(0);
+    l2.current_chain_length = 1;
+
+    // 1 can't be used as it has a 'b' key, but the head of the second level can
+    let (found_delta, found_pred) = compressor.get_delta(Some(1), 2);
+
+    let mut expected_delta: StateMap<Atom> = StateMap::new();
+    expected_delta.insert("c", "", "".into());
+
+    assert_eq!(found_delta, expected_delta);
+    assert_eq!(found_pred, Some(0));
+    assert_eq!(compressor.stats.resets_avoided, 1);
+}
+
+#[test]
+fn compress_keeps_chains_no_deeper_than_the_levels() {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    // This starts with two branches whose groups take turns
+    //
+    // 0-2-4-6-...-38
+    // 1-3-5-7-...-39
+    //
+    // Each group in the first branch adds ('a', i, i) and each group in the
+    // second adds ('b', i, i), so the heads of the levels are never a valid
+    // base for a group from the other branch (but its original predecessor
+    // always is)
+    for i in 0i64..40 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: if i < 2 { None } else { Some(i - 2) },
+            state_map: StateMap::new(),
+        };
+        let key = if i % 2 == 0 { "a" } else { "b" };
+        entry
+            .state_map
+            .insert(key, &i.to_string(), i.to_string().into());
+        initial.insert(i, entry);
+    }
+
+    let compressor = Compressor::compress(&initial, &[3, 3]);
+    let new_state_group_map = &compressor.new_state_group_map;
+
+    check_that_maps_match(&initial, new_state_group_map);
+
+    // The levels never make a chain deeper than the sum of their sizes, and
+    // the groups used in place of the suggested predecessor mustn't either
+    let mut depths = BTreeMap::new();
+    for sg in new_state_group_map.keys() {
+        assert!(depth_of(new_state_group_map, &mut depths, *sg) <= 6);
+    }
+}
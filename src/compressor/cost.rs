@@ -0,0 +1,310 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A compression strategy that picks whichever predecessor gives the smallest
+//! delta.
+//!
+//! The levels are still kept up to date in the same way as `LevelStrategy`, but
+//! rather than always using the head they suggest, every state group is
+//! compared against a handful of candidates:
+//!
+//! - the head suggested by the levels and its closest few ancestors
+//! - the heads of all of the other levels
+//! - the original predecessor of the state group and its closest few ancestors
+//!
+//! The candidate that needs the fewest rows is chosen, as long as doing so
+//! doesn't make the chain of predecessors any longer than using the suggested
+//! head would. This means that the chains are never longer than those that
+//! `LevelStrategy` would produce. If none of the candidates can be used then the
+//! rest of the suggested head's ancestors are tried (as `LevelStrategy` does)
+//! before falling back to storing the full state.
+
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{
+    backfill::BackfillDetector,
+    calculate_delta,
+    depth::depth_of,
+    pinned_fits_levels, place_in_levels,
+    snapshot::{self, SnapshotTracker},
+    CompressionStrategy, CompressorOptions, GroupOrder, Level, SnapshotPolicy, StateGroupEntry,
+    Stats,
+};
+use crate::state_cache::StateCache;
+
+/// How many ancestors of the original predecessor are considered as candidates
+const ANCESTORS_TO_CONSIDER: usize = 5;
+
+/// Chooses the predecessor for each state group that minimises the size of its
+/// delta (see the module documentation)
+pub struct CostStrategy {
+    levels: Vec<Level>,
+    options: CompressorOptions,
+}
+
+impl CostStrategy {
+    /// Creates a strategy with empty levels of the given maximum sizes
+    pub fn new(level_sizes: &[usize]) -> CostStrategy {
+        CostStrategy {
+            levels: level_sizes.iter().map(|size| Level::new(*size)).collect(),
+            options: CompressorOptions::default(),
+        }
+    }
+
+    /// Creates a strategy from the level state saved by a previous run
+    pub fn from_save(level_info: &[Level]) -> CostStrategy {
+        CostStrategy {
+            levels: level_info.to_vec(),
+            options: CompressorOptions::default(),
+        }
+    }
+
+    /// Sets all of the options at once, replacing any set before
+    pub fn with_options(mut self, options: CompressorOptions) -> CostStrategy {
+        self.options = options;
+        self
+    }
+
+    /// Sets the order the state groups are placed into the levels in
+    pub fn with_order(mut self, order: GroupOrder) -> CostStrategy {
+        self.options.order = order;
+        self
+    }
+
+    /// Sets when groups should store their full state regardless of the levels
+    pub fn with_snapshot_policy(mut self, snapshot_policy: SnapshotPolicy) -> CostStrategy {
+        self.options.snapshot_policy = snapshot_policy;
+        self
+    }
+
+    /// Sets the state groups that must be left exactly as they are (they are
+    /// only placed into the levels if their chains are no deeper than the
+    /// levels would have made them, but can always be used as predecessors when
+    /// they are no deeper than the suggested one)
+    pub fn with_pinned(mut self, pinned: BTreeSet<i64>) -> CostStrategy {
+        self.options.pinned = pinned;
+        self
+    }
+
+    /// Sets whether groups that look like they were backfilled should be left
+    /// as they are
+    pub fn with_skip_backfill(mut self, skip_backfill: bool) -> CostStrategy {
+        self.options.skip_backfill = skip_backfill;
+        self
+    }
+}
+
+impl CompressionStrategy for CostStrategy {
+    fn compress(
+        &mut self,
+        original_state_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> (BTreeMap<i64, StateGroupEntry>, Stats) {
+        let mut new_state_group_map: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+        let mut depths: BTreeMap<i64, usize> = BTreeMap::new();
+        let mut stats = Stats::default();
+        let mut state_cache = StateCache::new(original_state_map);
+
+        let pb: ProgressBar;
+        if cfg!(feature = "no-progress-bars") {
+            pb = ProgressBar::hidden();
+        } else {
+            pb = ProgressBar::new(original_state_map.len() as u64);
+        }
+        pb.set_style(
+            ProgressStyle::default_bar().template("[{elapsed_precise}] {bar} {pos}/{len} {msg}"),
+        );
+        pb.set_message("state groups");
+        pb.enable_steady_tick(100);
+
+        let mut backfill = BackfillDetector::new(self.options.skip_backfill, self.options.order);
+        let mut snapshots = SnapshotTracker::new(self.options.snapshot_policy);
+
+        for state_group in self.options.order.iter(original_state_map) {
+            let entry = &original_state_map[&state_group];
+
+            // Groups that aren't in range are only here because other groups
+            // depend on them, so are copied across unchanged
+            if !entry.in_range {
+                new_state_group_map.insert(state_group, entry.clone());
+                continue;
+            }
+
+            if backfill.is_backfill(&self.levels, entry.prev_state_group) {
+                backfill.add_backfilled(state_group);
+                stats.backfill_groups += 1;
+                new_state_group_map.insert(state_group, entry.clone());
+                pb.inc(1);
+                continue;
+            }
+
+            // Pinned groups keep their chains, which can be any length, so are
+            // only placed into the levels if that doesn't make the chains of
+            // the groups after them any deeper
+            if self.options.pinned.contains(&state_group) {
+                stats.pinned_groups += 1;
+                new_state_group_map.insert(state_group, entry.clone());
+
+                if pinned_fits_levels(&self.levels, &new_state_group_map, &mut depths, state_group)
+                {
+                    place_in_levels(&mut self.levels, state_group);
+                    backfill.add_placed(state_group);
+                }
+
+                pb.inc(1);
+                continue;
+            }
+
+            let level_heads: Vec<i64> = self.levels.iter().filter_map(|l| l.get_head()).collect();
+            let suggested = place_in_levels(&mut self.levels, state_group);
+            backfill.add_placed(state_group);
+
+            // The new group's chain mustn't be any longer than it would be if
+            // we used the suggested head
+            let max_depth = match suggested {
+                Some(s) => depth_of(&new_state_group_map, &mut depths, s) + 1,
+                None => 1,
+            };
+
+            // Build up the list of candidates, with the ones we'd prefer to use
+            // (if all else is equal) first
+            let mut candidates = Vec::new();
+            candidates.extend(entry.prev_state_group);
+            candidates.extend(suggested);
+            candidates.extend(level_heads);
+            candidates
+                .extend(ancestors(&new_state_group_map, suggested).take(ANCESTORS_TO_CONSIDER));
+            candidates.extend(
+                ancestors(&new_state_group_map, entry.prev_state_group).take(ANCESTORS_TO_CONSIDER),
+            );
+
+            let state_map = state_cache.get(state_group);
+
+            // Start off assuming that we'll have to store the full state
+            let mut best_prev = None;
+            let mut best_delta = None;
+            let mut best_size = state_map.len();
+
+            let mut seen = Vec::new();
+            for candidate in candidates {
+                if seen.contains(&candidate) {
+                    continue;
+                }
+                seen.push(candidate);
+
+                // Only groups that are already in the new map can be used, this
+                // also stops any loops from being created
+                if !new_state_group_map.contains_key(&candidate) {
+                    continue;
+                }
+
+                if depth_of(&new_state_group_map, &mut depths, candidate) >= max_depth {
+                    continue;
+                }
+
+                // Keeping the original predecessor means keeping the original
+                // rows, so that is what it costs
+                if Some(candidate) == entry.prev_state_group {
+                    if entry.state_map.len() < best_size || best_prev.is_none() {
+                        best_prev = Some(candidate);
+                        best_delta = None;
+                        best_size = entry.state_map.len();
+                    }
+                    continue;
+                }
+
+                let prev_state_map = state_cache.get(candidate);
+                if let Some(delta) = calculate_delta(&prev_state_map, &state_map) {
+                    if delta.len() < best_size || (best_prev.is_none() && delta.len() == best_size)
+                    {
+                        best_prev = Some(candidate);
+                        best_size = delta.len();
+                        best_delta = Some(delta);
+                    }
+                }
+            }
+
+            // If none of the candidates worked then keep walking up from the
+            // suggested head until we find something that does
+            if best_prev.is_none() {
+                for candidate in ancestors(&new_state_group_map, suggested) {
+                    let prev_state_map = state_cache.get(candidate);
+                    if let Some(delta) = calculate_delta(&prev_state_map, &state_map) {
+                        best_prev = Some(candidate);
+                        best_size = delta.len();
+                        best_delta = Some(delta);
+                        break;
+                    }
+                }
+            }
+
+            let mut is_snapshot = false;
+            if snapshots.needs_snapshot(&new_state_group_map, best_prev, best_size) {
+                stats.snapshots += 1;
+                snapshot::reset_levels(&mut self.levels, state_group);
+
+                is_snapshot = true;
+                best_prev = None;
+                best_delta = None;
+            }
+
+            let delta = if best_prev == entry.prev_state_group {
+                entry.state_map.clone()
+            } else {
+                stats.state_groups_changed += 1;
+
+                if best_prev.is_none() && !is_snapshot {
+                    stats.resets_no_suitable_prev += 1;
+                    stats.resets_no_suitable_prev_size += state_map.len();
+                }
+
+                best_delta.unwrap_or(state_map)
+            };
+
+            new_state_group_map.insert(
+                state_group,
+                StateGroupEntry {
+                    in_range: true,
+                    prev_state_group: best_prev,
+                    state_map: delta,
+                },
+            );
+
+            pb.inc(1);
+        }
+
+        pb.finish();
+
+        (new_state_group_map, stats)
+    }
+
+    fn get_level_info(&self) -> Vec<Level> {
+        self.levels.clone()
+    }
+}
+
+/// Iterates through the ancestors of a group in the new map (not including the
+/// group itself), starting with its predecessor
+fn ancestors(
+    new_state_group_map: &BTreeMap<i64, StateGroupEntry>,
+    state_group: Option<i64>,
+) -> impl Iterator<Item = i64> + '_ {
+    let mut next = state_group;
+    std::iter::from_fn(move || {
+        next = next
+            .and_then(|sg| new_state_group_map.get(&sg))
+            .and_then(|entry| entry.prev_state_group);
+        next
+    })
+}
@@ -0,0 +1,146 @@
+use crate::{
+    check_that_maps_match,
+    compressor::{CompressionStrategy, CostStrategy, LevelStrategy},
+    StateGroupEntry,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+
+/// Builds the following structure
+///
+/// 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+///
+/// Each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') where j is less than or equal to i
+fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=13i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+/// Returns the number of groups in the chain of predecessors of a group
+fn chain_length(map: &BTreeMap<i64, StateGroupEntry>, state_group: i64) -> usize {
+    let mut length = 1;
+    let mut entry = &map[&state_group];
+    while let Some(prev) = entry.prev_state_group {
+        length += 1;
+        entry = &map[&prev];
+    }
+    length
+}
+
+fn total_rows(map: &BTreeMap<i64, StateGroupEntry>) -> usize {
+    map.values().map(|e| e.state_map.len()).sum()
+}
+
+#[test]
+fn cost_strategy_produces_matching_maps() {
+    let initial = line_with_state();
+
+    let (new_state_group_map, _stats) = CostStrategy::new(&[3, 3]).compress(&initial);
+
+    check_that_maps_match(&initial, &new_state_group_map);
+}
+
+#[test]
+fn cost_strategy_respects_depth_bound() {
+    let initial = line_with_state();
+
+    let (new_state_group_map, _stats) = CostStrategy::new(&[3, 3]).compress(&initial);
+
+    for sg in new_state_group_map.keys() {
+        assert!(
+            chain_length(&new_state_group_map, *sg) <= 6,
+            "state group {} has too long a chain",
+            sg
+        );
+    }
+}
+
+#[test]
+fn cost_strategy_picks_smallest_delta() {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    // This starts with the following structure
+    //
+    // 0-1-2
+    //
+    // Where the state of 2 is the same as the state of 0 (e.g. a user left and
+    // then joined again). Both 1 and 2 have a 3 row delta.
+    for i in 0i64..=2i64 {
+        let value = if i == 1 { "1" } else { "0" };
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: if i == 0 { None } else { Some(i - 1) },
+            state_map: StateMap::new(),
+        };
+        entry.state_map.insert("a", "", value.into());
+        entry.state_map.insert("b", "", value.into());
+        entry.state_map.insert("c", "", value.into());
+
+        initial.insert(i, entry);
+    }
+
+    let (new_state_group_map, stats) = CostStrategy::new(&[3, 3]).compress(&initial);
+
+    // The levels suggest using 1 as the predecessor of 2 (as it originally was)
+    // but using 0 needs no rows at all
+    assert_eq!(new_state_group_map[&2].prev_state_group, Some(0));
+    assert!(new_state_group_map[&2].state_map.is_empty());
+    assert_eq!(stats.state_groups_changed, 1);
+
+    check_that_maps_match(&initial, &new_state_group_map);
+}
+
+#[test]
+fn cost_strategy_chains_no_longer_than_level_strategy() {
+    let initial = line_with_state();
+
+    let (cost_map, _stats) = CostStrategy::new(&[3, 3]).compress(&initial);
+    let (level_map, _stats) = LevelStrategy::new(&[3, 3]).compress(&initial);
+
+    for sg in initial.keys() {
+        assert!(chain_length(&cost_map, *sg) <= chain_length(&level_map, *sg));
+    }
+}
+
+#[test]
+fn cost_strategy_uses_no_more_rows_than_level_strategy() {
+    let initial = line_with_state();
+
+    let (cost_map, _stats) = CostStrategy::new(&[3, 3]).compress(&initial);
+    let (level_map, _stats) = LevelStrategy::new(&[3, 3]).compress(&initial);
+
+    assert!(total_rows(&cost_map) <= total_rows(&level_map));
+}
+
+#[test]
+fn cost_strategy_copies_groups_not_in_range() {
+    let mut initial = line_with_state();
+    for entry in initial.values_mut() {
+        entry.in_range = false;
+    }
+
+    let (new_state_group_map, stats) = CostStrategy::new(&[3, 3]).compress(&initial);
+
+    assert_eq!(new_state_group_map, initial);
+    assert_eq!(stats.state_groups_changed, 0);
+}
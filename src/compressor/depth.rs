@@ -0,0 +1,242 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Measuring and limiting the depth of the predecessor chains.
+//!
+//! The depth of a state group is the number of groups Synapse has to fetch to
+//! work out its full state, i.e. the group itself plus all of its predecessors.
+//! The level sizes only roughly bound this, as the chain lengths the levels
+//! keep track of are approximate and the chains of groups outside of the range
+//! being compressed can be any length. Since the loader fetches every missing
+//! predecessor, the whole chain of every group is in the map and the real depth
+//! can be worked out.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{
+    calculate_delta, order::dag_order, CompressionStrategy, Level, StateGroupEntry, Stats,
+};
+use crate::{state_cache::StateCache, StateGroupMap};
+
+/// Wraps another strategy, changing the predecessors of any state groups whose
+/// chains end up deeper than `max_depth`.
+///
+/// Each group that is too deep is rebased onto the deepest of its ancestors
+/// that keeps it within the limit. An ancestor is always a valid base for a
+/// delta so this never needs to store the full state (unless `max_depth` is 1).
+/// Only groups that are in range and not pinned are changed, so the limit is
+/// not enforced for the other groups.
+pub struct MaxDepthStrategy {
+    inner: Box<dyn CompressionStrategy>,
+    max_depth: usize,
+    pinned: BTreeSet<i64>,
+}
+
+impl MaxDepthStrategy {
+    /// Limits the depth of the chains produced by `inner` to `max_depth`
+    ///
+    /// Panics if `max_depth` is zero
+    pub fn new(inner: Box<dyn CompressionStrategy>, max_depth: usize) -> MaxDepthStrategy {
+        if max_depth == 0 {
+            panic!("The maximum depth must be at least 1");
+        }
+
+        MaxDepthStrategy {
+            inner,
+            max_depth,
+            pinned: BTreeSet::new(),
+        }
+    }
+
+    /// Sets the state groups that must be left exactly as they are
+    pub fn with_pinned(mut self, pinned: BTreeSet<i64>) -> MaxDepthStrategy {
+        self.pinned = pinned;
+        self
+    }
+}
+
+impl CompressionStrategy for MaxDepthStrategy {
+    fn compress(
+        &mut self,
+        original_state_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> (BTreeMap<i64, StateGroupEntry>, Stats) {
+        let (mut new_state_group_map, mut stats) = self.inner.compress(original_state_map);
+
+        limit_depth(
+            original_state_map,
+            &mut new_state_group_map,
+            self.max_depth,
+            &self.pinned,
+            &mut stats,
+        );
+
+        (new_state_group_map, stats)
+    }
+
+    fn get_level_info(&self) -> Vec<Level> {
+        self.inner.get_level_info()
+    }
+}
+
+/// Rebases the groups in `new_state_group_map` that are deeper than
+/// `max_depth` (see `MaxDepthStrategy`)
+///
+/// The groups are looked at depth first along the new predecessor links, so
+/// that every group is only looked at after all of its ancestors have been
+/// fixed. (Predecessors don't always have lower ids than the groups that point
+/// at them, e.g. when compressing in `GroupOrder::Dag`.)
+///
+/// # Arguments
+///
+/// * `original_state_map`  -   The state groups before compression
+/// * `new_state_group_map` -   The compressed state groups to change
+/// * `max_depth`           -   The deepest any chain in range is allowed to be
+/// * `pinned`              -   The groups that mustn't be changed
+/// * `stats`               -   The stats to record the changes in
+fn limit_depth(
+    original_state_map: &BTreeMap<i64, StateGroupEntry>,
+    new_state_group_map: &mut BTreeMap<i64, StateGroupEntry>,
+    max_depth: usize,
+    pinned: &BTreeSet<i64>,
+    stats: &mut Stats,
+) {
+    let mut depths: BTreeMap<i64, usize> = BTreeMap::new();
+    let mut state_cache = StateCache::new(original_state_map);
+    let groups = dag_order(new_state_group_map);
+
+    for sg in groups {
+        let depth = depth_of(new_state_group_map, &mut depths, sg);
+        if depth <= max_depth || !new_state_group_map[&sg].in_range || pinned.contains(&sg) {
+            continue;
+        }
+
+        // The ancestor at depth `max_depth - 1` is `depth - max_depth + 1`
+        // steps up the chain (and there isn't one if `max_depth` is 1)
+        let mut base = Some(sg);
+        for _ in 0..=(depth - max_depth) {
+            base = base.and_then(|b| new_state_group_map[&b].prev_state_group);
+        }
+
+        let state_map = state_cache.get(sg);
+        let delta = match base {
+            Some(b) => {
+                let base_state_map = state_cache.get(b);
+                calculate_delta(&base_state_map, &state_map)
+                    .expect("an ancestor should always be a valid base")
+            }
+            None => state_map,
+        };
+
+        let original_entry = &original_state_map[&sg];
+        let entry = new_state_group_map
+            .get_mut(&sg)
+            .expect("state group should be in the new map");
+
+        if *entry == *original_entry {
+            stats.state_groups_changed += 1;
+        }
+        stats.depth_limit_rebases += 1;
+
+        entry.prev_state_group = base;
+        entry.state_map = delta;
+        depths.insert(sg, max_depth);
+    }
+}
+
+/// Counts how many state groups there are at each depth
+pub fn depth_histogram<M: StateGroupMap>(state_group_map: &M) -> BTreeMap<usize, usize> {
+    let mut depths: BTreeMap<i64, usize> = BTreeMap::new();
+    let mut histogram = BTreeMap::new();
+
+    for sg in state_group_map.state_group_ids() {
+        let depth = depth_of(state_group_map, &mut depths, sg);
+        *histogram.entry(depth).or_insert(0) += 1;
+    }
+
+    histogram
+}
+
+/// An estimate of the work Synapse's recursive state query does to fetch the
+/// full state of the state groups being compressed. The query visits one group
+/// per iteration, so the number of iterations for a group is its depth
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QueryCost {
+    /// The average number of iterations needed for a state group in range
+    pub mean: f64,
+    /// The most iterations needed for any state group in range
+    pub max: usize,
+}
+
+/// Works out the query cost of the state groups in range (the groups outside
+/// of the range only count towards the depth of the groups based on them)
+pub fn query_cost<M: StateGroupMap>(state_group_map: &M) -> QueryCost {
+    let mut depths: BTreeMap<i64, usize> = BTreeMap::new();
+    let mut total = 0;
+    let mut count = 0;
+    let mut max = 0;
+
+    for sg in state_group_map.state_group_ids() {
+        if !state_group_map.group_in_range(sg) {
+            continue;
+        }
+        let depth = depth_of(state_group_map, &mut depths, sg);
+        total += depth;
+        count += 1;
+        max = max.max(depth);
+    }
+
+    let mean = if count == 0 {
+        0.0
+    } else {
+        total as f64 / count as f64
+    };
+
+    QueryCost { mean, max }
+}
+
+/// Returns the number of groups in the chain of predecessors starting at (and
+/// including) `state_group`, caching the results in `depths`
+pub(super) fn depth_of<M: StateGroupMap>(
+    state_group_map: &M,
+    depths: &mut BTreeMap<i64, usize>,
+    state_group: i64,
+) -> usize {
+    // Walk up the chain until we find a group that we already know the depth of
+    // (or we reach the start of the chain)
+    let mut chain = Vec::new();
+    let mut next = Some(state_group);
+    let mut depth = 0;
+
+    while let Some(sg) = next {
+        if let Some(d) = depths.get(&sg) {
+            depth = *d;
+            break;
+        }
+        chain.push(sg);
+        next = if state_group_map.has_group(sg) {
+            state_group_map.group_prev(sg)
+        } else {
+            None
+        };
+    }
+
+    // Then fill in the depths of everything on the way back down
+    for sg in chain.into_iter().rev() {
+        depth += 1;
+        depths.insert(sg, depth);
+    }
+
+    depth
+}
@@ -0,0 +1,183 @@
+use crate::{
+    check_that_maps_match,
+    compressor::{
+        depth_histogram, query_cost, CompressionStrategy, Level, LevelStrategy, MaxDepthStrategy,
+    },
+    StateGroupEntry,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+
+/// Builds the following structure
+///
+/// 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+///
+/// Each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') where j is less than or equal to i
+fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=13i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+#[test]
+fn depth_histogram_counts_whole_chain() {
+    let mut initial = line_with_state();
+
+    // Groups outside of the range still count towards the depth
+    for i in 0i64..=9i64 {
+        initial.get_mut(&i).unwrap().in_range = false;
+    }
+
+    let histogram = depth_histogram(&initial);
+
+    let expected: BTreeMap<usize, usize> = (1..=14).map(|d| (d, 1)).collect();
+    assert_eq!(histogram, expected);
+}
+
+#[test]
+fn max_depth_strategy_limits_depth() {
+    let initial = line_with_state();
+
+    // Levels this big would leave the line as it is
+    let inner = Box::new(LevelStrategy::new(&[20, 20]));
+    let mut strategy = MaxDepthStrategy::new(inner, 4);
+    let (new_state_group_map, stats) = strategy.compress(&initial);
+
+    // Groups 4 onwards are moved onto the group at depth 3 of their chain,
+    // so the new structure is
+    //
+    // 0-1-2-3
+    //      \-4
+    //      \-5
+    //       ...
+    //      \-13
+    for sg in 4i64..=13i64 {
+        assert_eq!(new_state_group_map[&sg].prev_state_group, Some(2));
+    }
+
+    let histogram = depth_histogram(&new_state_group_map);
+    assert_eq!(histogram.keys().last(), Some(&4));
+
+    assert_eq!(stats.depth_limit_rebases, 10);
+    assert_eq!(stats.state_groups_changed, 10);
+
+    check_that_maps_match(&initial, &new_state_group_map);
+}
+
+#[test]
+fn max_depth_strategy_stores_full_state_if_max_depth_one() {
+    let initial = line_with_state();
+
+    let inner = Box::new(LevelStrategy::new(&[3, 3]));
+    let mut strategy = MaxDepthStrategy::new(inner, 1);
+    let (new_state_group_map, _stats) = strategy.compress(&initial);
+
+    for entry in new_state_group_map.values() {
+        assert_eq!(entry.prev_state_group, None);
+    }
+
+    check_that_maps_match(&initial, &new_state_group_map);
+}
+
+#[test]
+fn max_depth_strategy_does_not_change_groups_not_in_range() {
+    let mut initial = line_with_state();
+
+    for i in 0i64..=9i64 {
+        initial.get_mut(&i).unwrap().in_range = false;
+    }
+
+    // Continue on from 9 (as if 0-9 were compressed in an earlier run) so
+    // that the levels leave the line as it is
+    let level_info = vec![
+        Level::restore(20, 10, Some(9)),
+        Level::restore(20, 1, Some(9)),
+    ];
+    let inner = Box::new(LevelStrategy::from_save(&level_info));
+    let mut strategy = MaxDepthStrategy::new(inner, 5);
+    let (new_state_group_map, stats) = strategy.compress(&initial);
+
+    // 0-9 are left alone (even though 5-9 are too deep) and 10-13 are moved
+    // onto 3, which is at depth 4
+    for sg in 0i64..=9i64 {
+        assert_eq!(new_state_group_map[&sg], initial[&sg]);
+    }
+    for sg in 10i64..=13i64 {
+        assert_eq!(new_state_group_map[&sg].prev_state_group, Some(3));
+    }
+
+    assert_eq!(stats.depth_limit_rebases, 4);
+
+    check_that_maps_match(&initial, &new_state_group_map);
+}
+
+#[test]
+fn max_depth_strategy_leaves_shallow_trees_alone() {
+    let initial = line_with_state();
+
+    let (level_map, level_stats) = LevelStrategy::new(&[3, 3]).compress(&initial);
+
+    // Two levels of size 3 never produce chains longer than 6 groups
+    let inner = Box::new(LevelStrategy::new(&[3, 3]));
+    let mut strategy = MaxDepthStrategy::new(inner, 6);
+    let (new_state_group_map, stats) = strategy.compress(&initial);
+
+    assert_eq!(new_state_group_map, level_map);
+    assert_eq!(stats.depth_limit_rebases, 0);
+    assert_eq!(stats.state_groups_changed, level_stats.state_groups_changed);
+}
+
+#[test]
+#[should_panic(expected = "The maximum depth must be at least 1")]
+fn max_depth_strategy_panics_if_max_depth_zero() {
+    MaxDepthStrategy::new(Box::new(LevelStrategy::new(&[3, 3])), 0);
+}
+
+#[test]
+fn query_cost_only_counts_groups_in_range() {
+    let mut initial = line_with_state();
+
+    // Groups 10 to 13 have depths 11 to 14
+    for i in 0i64..=9i64 {
+        initial.get_mut(&i).unwrap().in_range = false;
+    }
+
+    let cost = query_cost(&initial);
+
+    assert_eq!(cost.max, 14);
+    assert!((cost.mean - 12.5).abs() < 1e-9);
+}
+
+#[test]
+fn query_cost_goes_down_after_compression() {
+    let initial = line_with_state();
+
+    let (new_state_group_map, _) = LevelStrategy::new(&[3, 3]).compress(&initial);
+
+    let before = query_cost(&initial);
+    let after = query_cost(&new_state_group_map);
+
+    assert_eq!(before.max, 14);
+    assert!((before.mean - 7.5).abs() < 1e-9);
+    assert!(after.max < before.max);
+    assert!(after.mean < before.mean);
+}
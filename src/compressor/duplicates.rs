@@ -0,0 +1,222 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Finding state groups with identical state.
+//!
+//! Synapse creates a new state group for lots of events that don't change the
+//! state of the room, so many groups end up with exactly the same collapsed
+//! state as an earlier one. The level strategy still gives each of these a
+//! delta on the head of a level, which can be many rows. Pointing them at the
+//! earlier group instead means they don't take up any rows at all.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    str::FromStr,
+};
+
+use super::{depth::depth_of, CompressionStrategy, Level, Stats};
+use crate::{state_cache::StateCache, StateGroupEntry, StateGroupMap};
+
+/// What to do with the state groups that have the same state as an earlier one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateMode {
+    /// Count them in the statistics, but leave them as the strategy left them
+    Report,
+    /// Make each of them a child of the earliest group with the same state,
+    /// with an empty delta
+    Merge,
+}
+
+impl FromStr for DuplicateMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(DuplicateMode::Report),
+            "merge" => Ok(DuplicateMode::Merge),
+            _ => Err("Unknown duplicates mode"),
+        }
+    }
+}
+
+/// Finds the state groups in range whose full state is the same as that of a
+/// group with a lower ID
+///
+/// Returns a map from each of these duplicates to its canonical group, which
+/// is the group with the lowest ID that has the same state. The canonical
+/// group may be out of range, but is never a duplicate itself.
+pub fn find_duplicates<M: StateGroupMap>(state_group_map: &M) -> BTreeMap<i64, i64> {
+    let mut state_cache = StateCache::new(state_group_map);
+    let mut duplicates = BTreeMap::new();
+
+    // The canonical groups, keyed by the hash of their state
+    let mut canonical: HashMap<u64, Vec<i64>> = HashMap::new();
+
+    for sg in state_group_map.state_group_ids() {
+        let state = state_cache.get(sg);
+        let candidates = canonical.entry(M::state_hash(&state)).or_default();
+
+        // Different states can have the same hash, so the states of the
+        // candidates still have to be compared
+        let found = candidates
+            .iter()
+            .copied()
+            .find(|candidate| state_cache.get(*candidate) == state);
+
+        match found {
+            Some(canonical_sg) => {
+                if state_group_map.group_in_range(sg) {
+                    duplicates.insert(sg, canonical_sg);
+                }
+            }
+            None => candidates.push(sg),
+        }
+    }
+
+    duplicates
+}
+
+/// Wraps another strategy, finding the state groups with the same state as an
+/// earlier group and (depending on the mode) making them point at it.
+///
+/// The duplicates are counted in `Stats::duplicate_groups`. When merging, the
+/// pinned groups are left alone, as is any duplicate that the new chain of its
+/// canonical group passes through (since pointing at it would make a loop).
+/// A merged group's chain is one longer than its canonical group's, so any
+/// duplicate that this would make deeper than it was is left alone too.
+pub struct DuplicatesStrategy {
+    inner: Box<dyn CompressionStrategy>,
+    mode: DuplicateMode,
+    pinned: BTreeSet<i64>,
+}
+
+impl DuplicatesStrategy {
+    /// Looks for duplicates in the state groups compressed by `inner`
+    pub fn new(inner: Box<dyn CompressionStrategy>, mode: DuplicateMode) -> DuplicatesStrategy {
+        DuplicatesStrategy {
+            inner,
+            mode,
+            pinned: BTreeSet::new(),
+        }
+    }
+
+    /// Sets the state groups that must be left exactly as they are
+    pub fn with_pinned(mut self, pinned: BTreeSet<i64>) -> DuplicatesStrategy {
+        self.pinned = pinned;
+        self
+    }
+}
+
+impl CompressionStrategy for DuplicatesStrategy {
+    fn compress(
+        &mut self,
+        original_state_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> (BTreeMap<i64, StateGroupEntry>, Stats) {
+        let (mut new_state_group_map, mut stats) = self.inner.compress(original_state_map);
+
+        let duplicates = find_duplicates(original_state_map);
+        stats.duplicate_groups += duplicates.len();
+
+        if self.mode == DuplicateMode::Merge {
+            merge_duplicates(
+                original_state_map,
+                &mut new_state_group_map,
+                &duplicates,
+                &self.pinned,
+                &mut stats,
+            );
+        }
+
+        (new_state_group_map, stats)
+    }
+
+    fn get_level_info(&self) -> Vec<Level> {
+        self.inner.get_level_info()
+    }
+}
+
+/// Makes each duplicate in `new_state_group_map` a child of its canonical
+/// group with an empty delta (see `DuplicatesStrategy`)
+///
+/// Duplicates whose chain would get deeper by doing so are left alone, so that
+/// merging never makes the chains any longer than the strategy left them
+///
+/// # Arguments
+///
+/// * `original_state_map`  -   The state groups before compression
+/// * `new_state_group_map` -   The compressed state groups to change
+/// * `duplicates`          -   The canonical group of each duplicate, as
+///                             returned by `find_duplicates`
+/// * `pinned`              -   The groups that mustn't be changed
+/// * `stats`               -   The stats to record the changes in
+fn merge_duplicates(
+    original_state_map: &BTreeMap<i64, StateGroupEntry>,
+    new_state_group_map: &mut BTreeMap<i64, StateGroupEntry>,
+    duplicates: &BTreeMap<i64, i64>,
+    pinned: &BTreeSet<i64>,
+    stats: &mut Stats,
+) {
+    for (&sg, &canonical_sg) in duplicates {
+        if pinned.contains(&sg) || is_ancestor(new_state_group_map, sg, canonical_sg) {
+            continue;
+        }
+
+        let mut depths = BTreeMap::new();
+        if depth_of(new_state_group_map, &mut depths, canonical_sg) + 1
+            > depth_of(new_state_group_map, &mut depths, sg)
+        {
+            continue;
+        }
+
+        let merged = StateGroupEntry {
+            in_range: true,
+            prev_state_group: Some(canonical_sg),
+            state_map: Default::default(),
+        };
+
+        let original_entry = &original_state_map[&sg];
+        let entry = new_state_group_map
+            .get_mut(&sg)
+            .expect("state group should be in the new map");
+
+        // The strategy may already have changed the group, or it may have
+        // been a child of its canonical group to begin with
+        let was_changed = *entry != *original_entry;
+        let is_changed = merged != *original_entry;
+        if is_changed && !was_changed {
+            stats.state_groups_changed += 1;
+        } else if was_changed && !is_changed {
+            stats.state_groups_changed -= 1;
+        }
+
+        *entry = merged;
+    }
+}
+
+/// Whether `ancestor` is `state_group` or one of its predecessors
+fn is_ancestor(
+    state_group_map: &BTreeMap<i64, StateGroupEntry>,
+    ancestor: i64,
+    state_group: i64,
+) -> bool {
+    let mut next = Some(state_group);
+    while let Some(sg) = next {
+        if sg == ancestor {
+            return true;
+        }
+        next = state_group_map[&sg].prev_state_group;
+    }
+
+    false
+}
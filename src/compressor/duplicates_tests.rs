@@ -0,0 +1,250 @@
+use crate::{
+    check_that_maps_match,
+    compressor::{
+        depth::depth_of, find_duplicates, query_cost, CompressionStrategy, DuplicateMode,
+        DuplicatesStrategy, Level, LevelStrategy, Stats,
+    },
+    CompactStateGroupMap, StateGroupEntry,
+};
+use state_map::StateMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
+
+/// Builds the following structure
+///
+/// 0-1-2-3-4-5-6 7-8-9
+///
+/// Each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') where j is less than or equal to i
+///
+/// except that 3 and 4 have empty deltas (so have the same state as 2), and 7
+/// has the same state as 5 (but stores it in full)
+fn line_with_duplicates() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=9i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+
+        match i {
+            3 | 4 => {}
+            7 => {
+                entry.prev_state_group = None;
+                for j in &[0, 1, 2, 5] {
+                    entry
+                        .state_map
+                        .insert("group", &j.to_string(), "seen".into());
+                }
+                entry.state_map.insert("node", "is", "5".into());
+            }
+            _ => {
+                entry
+                    .state_map
+                    .insert("group", &i.to_string(), "seen".into());
+                entry.state_map.insert("node", "is", i.to_string().into());
+            }
+        }
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+fn expected_duplicates() -> BTreeMap<i64, i64> {
+    vec![(3, 2), (4, 2), (7, 5)].into_iter().collect()
+}
+
+/// A strategy that always returns the same map
+struct FixedStrategy(BTreeMap<i64, StateGroupEntry>);
+
+impl CompressionStrategy for FixedStrategy {
+    fn compress(
+        &mut self,
+        _original_state_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> (BTreeMap<i64, StateGroupEntry>, Stats) {
+        (self.0.clone(), Stats::default())
+    }
+
+    fn get_level_info(&self) -> Vec<Level> {
+        Vec::new()
+    }
+}
+
+#[test]
+fn duplicate_mode_from_str_parses_modes() {
+    assert_eq!(DuplicateMode::from_str("report"), Ok(DuplicateMode::Report));
+    assert_eq!(DuplicateMode::from_str("merge"), Ok(DuplicateMode::Merge));
+    assert!(DuplicateMode::from_str("sheep").is_err());
+}
+
+#[test]
+fn find_duplicates_finds_groups_with_the_same_state() {
+    let initial = line_with_duplicates();
+
+    assert_eq!(find_duplicates(&initial), expected_duplicates());
+}
+
+#[test]
+fn find_duplicates_ignores_groups_out_of_range() {
+    let mut initial = line_with_duplicates();
+    for sg in 0..=3 {
+        initial.get_mut(&sg).unwrap().in_range = false;
+    }
+
+    // 3 isn't reported, but 2 is still the canonical group for 4
+    let expected: BTreeMap<i64, i64> = vec![(4, 2), (7, 5)].into_iter().collect();
+    assert_eq!(find_duplicates(&initial), expected);
+}
+
+#[test]
+fn find_duplicates_works_on_compact_maps() {
+    let initial = line_with_duplicates();
+    let compact = CompactStateGroupMap::from_map(&initial);
+
+    assert_eq!(find_duplicates(&compact), expected_duplicates());
+}
+
+#[test]
+fn report_counts_duplicates_without_changing_anything() {
+    let initial = line_with_duplicates();
+
+    let (without, without_stats) = LevelStrategy::new(&[3, 3]).compress(&initial);
+
+    let inner = Box::new(LevelStrategy::new(&[3, 3]));
+    let mut strategy = DuplicatesStrategy::new(inner, DuplicateMode::Report);
+    let (with, with_stats) = strategy.compress(&initial);
+
+    assert_eq!(with, without);
+    assert_eq!(with_stats.duplicate_groups, 3);
+    assert_eq!(
+        with_stats.state_groups_changed,
+        without_stats.state_groups_changed
+    );
+}
+
+#[test]
+fn merge_points_duplicates_at_their_canonical_group() {
+    let initial = line_with_duplicates();
+
+    // With levels this big none of the duplicates are any deeper than their
+    // canonical groups, so they can all be merged
+    let inner = Box::new(LevelStrategy::new(&[20]));
+    let mut strategy = DuplicatesStrategy::new(inner, DuplicateMode::Merge);
+    let (new_state, stats) = strategy.compress(&initial);
+
+    for (sg, canonical_sg) in expected_duplicates() {
+        assert_eq!(new_state[&sg].prev_state_group, Some(canonical_sg));
+        assert!(new_state[&sg].state_map.is_empty());
+    }
+    assert_eq!(stats.duplicate_groups, 3);
+
+    check_that_maps_match(&initial, &new_state);
+}
+
+#[test]
+fn merge_counts_changes_once() {
+    let initial = line_with_duplicates();
+
+    // With levels this big the line is left as it is, apart from 7 which is
+    // made a delta on 5 (as 6 has keys that 7 doesn't). So the only changes
+    // made by merging are to 3 (which is already on 2) and to 4
+    let inner = Box::new(LevelStrategy::new(&[20]));
+    let mut strategy = DuplicatesStrategy::new(inner, DuplicateMode::Merge);
+    let (new_state, stats) = strategy.compress(&initial);
+
+    assert_eq!(new_state[&3], initial[&3]);
+    assert_eq!(new_state[&4].prev_state_group, Some(2));
+    assert_eq!(new_state[&7].prev_state_group, Some(5));
+    assert_eq!(stats.state_groups_changed, 2);
+}
+
+#[test]
+fn merge_leaves_pinned_groups_alone() {
+    let initial = line_with_duplicates();
+
+    let inner = Box::new(FixedStrategy(initial.clone()));
+    let pinned: BTreeSet<i64> = vec![7].into_iter().collect();
+    let mut strategy = DuplicatesStrategy::new(inner, DuplicateMode::Merge).with_pinned(pinned);
+    let (new_state, stats) = strategy.compress(&initial);
+
+    // 3 is already on 2, so only 4 is changed
+    assert_eq!(new_state[&7], initial[&7]);
+    assert_eq!(new_state[&4].prev_state_group, Some(2));
+    assert_eq!(stats.duplicate_groups, 3);
+    assert_eq!(stats.state_groups_changed, 1);
+}
+
+#[test]
+fn merge_does_not_make_loops() {
+    let initial = line_with_duplicates();
+
+    // Make 2 a child of 3 (its duplicate) and give 3 the full state
+    let mut reversed = initial.clone();
+    let state_of_2 = crate::collapse_state_maps(&initial, 2);
+    reversed.insert(
+        3,
+        StateGroupEntry {
+            in_range: true,
+            prev_state_group: None,
+            state_map: state_of_2,
+        },
+    );
+    reversed.insert(
+        2,
+        StateGroupEntry {
+            in_range: true,
+            prev_state_group: Some(3),
+            state_map: StateMap::new(),
+        },
+    );
+
+    let inner = Box::new(FixedStrategy(reversed.clone()));
+    let mut strategy = DuplicatesStrategy::new(inner, DuplicateMode::Merge);
+    let (new_state, _) = strategy.compress(&initial);
+
+    // 3 is left as it was. So is 4, as pointing it at 2 (which is now based
+    // on 3) would make its chain longer
+    assert_eq!(new_state[&3], reversed[&3]);
+    assert_eq!(new_state[&4], reversed[&4]);
+
+    check_that_maps_match(&initial, &new_state);
+}
+
+#[test]
+fn merge_does_not_make_chains_deeper() {
+    let initial = line_with_duplicates();
+
+    let (without, _) = LevelStrategy::new(&[3, 3]).compress(&initial);
+
+    let inner = Box::new(LevelStrategy::new(&[3, 3]));
+    let mut strategy = DuplicatesStrategy::new(inner, DuplicateMode::Merge);
+    let (with, _) = strategy.compress(&initial);
+
+    // The levels start a new chain at 3, so it (and 4 after it) would be
+    // deeper on 2 than they are now
+    assert_eq!(with[&3], without[&3]);
+    assert_eq!(with[&4], without[&4]);
+
+    let mut depths_without = BTreeMap::new();
+    let mut depths_with = BTreeMap::new();
+    for sg in initial.keys() {
+        assert!(
+            depth_of(&with, &mut depths_with, *sg) <= depth_of(&without, &mut depths_without, *sg),
+            "state group {} got deeper",
+            sg
+        );
+    }
+    assert!(query_cost(&with).max <= query_cost(&without).max);
+
+    check_that_maps_match(&initial, &with);
+}
@@ -0,0 +1,158 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Undoing compression.
+//!
+//! This rewrites the state groups in range into a simple shape, which is
+//! useful when debugging or before migrating the data elsewhere. It almost
+//! always adds rows, so it is run without the checks that stop a compression
+//! that doesn't save anything from being written out.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
+
+use super::{CompressionStrategy, Level, Stats};
+use crate::{state_cache::StateCache, CompactStateGroupMap, StateGroupEntry, StateGroupMap};
+
+/// The shape to rewrite the state groups into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlattenMode {
+    /// Every group stores its full state and has no predecessor
+    Snapshots,
+    /// Each group is a delta on the group before it (in order of ID). A
+    /// group stores its full state if it is the first one, or if it doesn't
+    /// have all of the state keys of the group before it
+    Chain,
+}
+
+impl FromStr for FlattenMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snapshots" => Ok(FlattenMode::Snapshots),
+            "chain" => Ok(FlattenMode::Chain),
+            _ => Err("Unknown flatten mode"),
+        }
+    }
+}
+
+/// Rewrites the state groups in range into the given shape (see `FlattenMode`)
+///
+/// The groups that aren't in range, and the pinned groups, are copied across
+/// unchanged
+pub fn flatten<M: StateGroupMap>(
+    state_group_map: &M,
+    mode: FlattenMode,
+    pinned: &BTreeSet<i64>,
+) -> (M, Stats) {
+    let mut new_state_group_map = state_group_map.empty_copy();
+    let mut stats = Stats::default();
+    let mut state_cache = StateCache::new(state_group_map);
+
+    // The previous group in range and its full state (used by `Chain`)
+    let mut last: Option<(i64, M::State)> = None;
+
+    for sg in state_group_map.state_group_ids() {
+        let original_prev = state_group_map.group_prev(sg);
+        let original_delta = state_group_map.group_delta(sg);
+
+        if !state_group_map.group_in_range(sg) {
+            new_state_group_map.add_group(sg, false, original_prev, original_delta.clone());
+            continue;
+        }
+
+        let state = state_cache.get(sg);
+
+        if pinned.contains(&sg) {
+            stats.pinned_groups += 1;
+            new_state_group_map.add_group(sg, true, original_prev, original_delta.clone());
+
+            // The next group in the chain can still be based on it
+            if mode == FlattenMode::Chain {
+                last = Some((sg, state));
+            }
+            continue;
+        }
+
+        let (prev, delta) = match (mode, &last) {
+            (FlattenMode::Chain, Some((last_sg, last_state))) => {
+                match M::calculate_delta(last_state, &state) {
+                    Some(delta) => (Some(*last_sg), delta),
+                    None => (None, state.clone()),
+                }
+            }
+            _ => (None, state.clone()),
+        };
+
+        if mode == FlattenMode::Chain {
+            last = Some((sg, state));
+        }
+
+        if prev == original_prev {
+            new_state_group_map.add_group(sg, true, original_prev, original_delta.clone());
+        } else {
+            stats.state_groups_changed += 1;
+            new_state_group_map.add_group(sg, true, prev, delta);
+        }
+    }
+
+    (new_state_group_map, stats)
+}
+
+/// Rewrites the state groups using `flatten`, so that it can be run in place of
+/// a compression strategy
+pub struct FlattenStrategy {
+    mode: FlattenMode,
+    pinned: BTreeSet<i64>,
+}
+
+impl FlattenStrategy {
+    /// Creates a strategy that flattens the state groups into the given shape
+    pub fn new(mode: FlattenMode) -> FlattenStrategy {
+        FlattenStrategy {
+            mode,
+            pinned: BTreeSet::new(),
+        }
+    }
+
+    /// Sets the state groups that must be left exactly as they are
+    pub fn with_pinned(mut self, pinned: BTreeSet<i64>) -> FlattenStrategy {
+        self.pinned = pinned;
+        self
+    }
+}
+
+impl CompressionStrategy for FlattenStrategy {
+    fn compress(
+        &mut self,
+        original_state_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> (BTreeMap<i64, StateGroupEntry>, Stats) {
+        flatten(original_state_map, self.mode, &self.pinned)
+    }
+
+    fn compress_compact(
+        &mut self,
+        original_state_map: &CompactStateGroupMap,
+    ) -> (CompactStateGroupMap, Stats) {
+        flatten(original_state_map, self.mode, &self.pinned)
+    }
+
+    // There are no levels
+    fn get_level_info(&self) -> Vec<Level> {
+        Vec::new()
+    }
+}
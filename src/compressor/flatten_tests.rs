@@ -0,0 +1,129 @@
+use crate::{
+    check_that_maps_match,
+    compressor::{flatten, Compressor, FlattenMode},
+    CompactStateGroupMap, StateGroupEntry,
+};
+use state_map::StateMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    str::FromStr,
+};
+
+/// Builds the following structure
+///
+/// 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+///
+/// Each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') where j is less than or equal to i
+fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=13i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+#[test]
+fn flatten_mode_from_str_parses_modes() {
+    assert_eq!(
+        FlattenMode::from_str("snapshots"),
+        Ok(FlattenMode::Snapshots)
+    );
+    assert_eq!(FlattenMode::from_str("chain"), Ok(FlattenMode::Chain));
+    assert!(FlattenMode::from_str("sheep").is_err());
+}
+
+#[test]
+fn flatten_snapshots_gives_every_group_its_full_state() {
+    let initial = line_with_state();
+    let compressed = Compressor::compress(&initial, &[3, 3]).new_state_group_map;
+
+    let (new_state, stats) = flatten(&compressed, FlattenMode::Snapshots, &BTreeSet::new());
+
+    for (sg, entry) in &new_state {
+        assert_eq!(entry.prev_state_group, None);
+        // Group i has i + 1 'group' entries and one 'node' entry
+        assert_eq!(entry.state_map.len(), *sg as usize + 2);
+    }
+
+    // 0, 3 and 12 were already snapshots
+    assert_eq!(stats.state_groups_changed, 11);
+
+    check_that_maps_match(&compressed, &new_state);
+}
+
+#[test]
+fn flatten_chain_undoes_compression() {
+    let initial = line_with_state();
+    let compressed = Compressor::compress(&initial, &[3, 3]).new_state_group_map;
+
+    let (new_state, stats) = flatten(&compressed, FlattenMode::Chain, &BTreeSet::new());
+
+    assert_eq!(new_state, initial);
+
+    // 3, 6, 9 and 12 are the only groups that weren't based on the group before
+    assert_eq!(stats.state_groups_changed, 4);
+}
+
+#[test]
+fn flatten_chain_starts_again_if_keys_are_removed() {
+    let mut initial = line_with_state();
+
+    // Make 7 a new root without any of the earlier 'group' entries
+    let entry = initial.get_mut(&7).unwrap();
+    entry.prev_state_group = None;
+    entry.state_map = StateMap::new();
+    entry.state_map.insert("node", "is", "7".into());
+
+    let (new_state, _) = flatten(&initial, FlattenMode::Chain, &BTreeSet::new());
+
+    assert_eq!(new_state, initial);
+}
+
+#[test]
+fn flatten_leaves_groups_out_of_range_alone() {
+    let initial = line_with_state();
+    let mut compressed = Compressor::compress(&initial, &[3, 3]).new_state_group_map;
+
+    for sg in 0..=8 {
+        compressed.get_mut(&sg).unwrap().in_range = false;
+    }
+
+    let (new_state, _) = flatten(&compressed, FlattenMode::Snapshots, &BTreeSet::new());
+
+    for sg in 0..=8 {
+        assert_eq!(new_state[&sg], compressed[&sg]);
+    }
+    for sg in 9..=13 {
+        assert_eq!(new_state[&sg].prev_state_group, None);
+    }
+
+    check_that_maps_match(&compressed, &new_state);
+}
+
+#[test]
+fn flatten_works_on_compact_maps() {
+    let initial = line_with_state();
+    let compressed = Compressor::compress(&initial, &[3, 3]).new_state_group_map;
+    let compact = CompactStateGroupMap::from_map(&compressed);
+
+    let (new_state, _) = flatten(&compact, FlattenMode::Chain, &BTreeSet::new());
+
+    assert_eq!(new_state.to_map(), initial);
+}
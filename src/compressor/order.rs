@@ -0,0 +1,93 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The order in which the state groups are placed into the levels.
+//!
+//! By default the groups are taken in order of ID, which assumes that groups
+//! with neighbouring IDs are close together in the room's history. That isn't
+//! true of rooms with forks, backfill or lots of federation traffic, where
+//! consecutive IDs can belong to different branches of the DAG formed by the
+//! original `prev_state_group` links. The levels then end up alternating
+//! between branches, which gives big deltas.
+//!
+//! The DAG order instead walks the original predecessor links depth first from
+//! each root, so that each branch is placed into the levels in one go. A group
+//! is still always placed after its original predecessor.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use crate::StateGroupMap;
+
+/// The order in which the compressor works through the state groups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GroupOrder {
+    /// In ascending order of ID
+    #[default]
+    Id,
+    /// Depth first along the original predecessor links (see the module
+    /// documentation)
+    Dag,
+}
+
+impl FromStr for GroupOrder {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "id" => Ok(GroupOrder::Id),
+            "dag" => Ok(GroupOrder::Dag),
+            _ => Err("Unknown state group order"),
+        }
+    }
+}
+
+impl GroupOrder {
+    /// Iterates through the IDs of the groups in `state_group_map` in this order
+    pub fn iter<M: StateGroupMap>(self, state_group_map: &M) -> Box<dyn Iterator<Item = i64> + '_> {
+        match self {
+            GroupOrder::Id => state_group_map.state_group_ids(),
+            GroupOrder::Dag => Box::new(dag_order(state_group_map).into_iter()),
+        }
+    }
+}
+
+/// Lists the groups in `state_group_map` depth first along the predecessor
+/// links, starting from the groups whose predecessors aren't in the map. The
+/// roots and the successors of each group are taken in order of ID
+pub(super) fn dag_order<M: StateGroupMap>(state_group_map: &M) -> Vec<i64> {
+    let mut successors: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+    let mut roots = Vec::new();
+
+    for sg in state_group_map.state_group_ids() {
+        match state_group_map.group_prev(sg) {
+            Some(prev) if state_group_map.has_group(prev) => {
+                successors.entry(prev).or_default().push(sg)
+            }
+            _ => roots.push(sg),
+        }
+    }
+
+    let mut order = Vec::with_capacity(state_group_map.group_count());
+
+    // The stack is popped from the end, so everything is pushed in reverse
+    let mut stack: Vec<i64> = roots.into_iter().rev().collect();
+    while let Some(sg) = stack.pop() {
+        order.push(sg);
+        if let Some(next) = successors.remove(&sg) {
+            stack.extend(next.into_iter().rev());
+        }
+    }
+
+    order
+}
@@ -0,0 +1,146 @@
+use crate::{
+    check_that_maps_match,
+    compressor::{
+        order::dag_order, query_cost, CompressionStrategy, CostStrategy, GroupOrder, LevelStrategy,
+        MaxDepthStrategy,
+    },
+    CompactStateGroupMap, StateGroupEntry, StateGroupMap,
+};
+use state_map::StateMap;
+use std::{collections::BTreeMap, str::FromStr};
+
+/// Builds the following structure, where the two branches have interleaved ids
+///
+///   /-1-3-5-7-9-11-13
+/// 0
+///   \-2-4-6-8-10-12-14
+///
+/// Each group i has state:
+///     ('node',  'is',      i)
+///     ('odd',   j,    'seen') for each odd j on the path to i
+///     ('even',  j,    'seen') for each even j on the path to i (not including 0)
+fn interleaved_branches() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    let mut entry = StateGroupEntry {
+        in_range: true,
+        prev_state_group: None,
+        state_map: StateMap::new(),
+    };
+    entry.state_map.insert("node", "is", "0".into());
+    initial.insert(0, entry);
+
+    for i in 1i64..=14i64 {
+        let branch = if i % 2 == 1 { "odd" } else { "even" };
+        let prev = if i <= 2 { 0 } else { i - 2 };
+
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: Some(prev),
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert(branch, &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+    }
+
+    initial
+}
+
+#[test]
+fn group_order_from_str() {
+    assert_eq!(GroupOrder::from_str("id"), Ok(GroupOrder::Id));
+    assert_eq!(GroupOrder::from_str("dag"), Ok(GroupOrder::Dag));
+    assert!(GroupOrder::from_str("random").is_err());
+    assert_eq!(GroupOrder::default(), GroupOrder::Id);
+}
+
+#[test]
+fn dag_order_follows_branches() {
+    let initial = interleaved_branches();
+
+    let order = dag_order(&initial);
+
+    assert_eq!(
+        order,
+        vec![0, 1, 3, 5, 7, 9, 11, 13, 2, 4, 6, 8, 10, 12, 14]
+    );
+}
+
+#[test]
+fn dag_order_starts_from_groups_with_missing_predecessors() {
+    let mut initial = interleaved_branches();
+
+    // Without 0 both branches are roots (and 2 comes after 1)
+    initial.remove(&0);
+
+    let order = dag_order(&initial);
+
+    assert_eq!(order, vec![1, 3, 5, 7, 9, 11, 13, 2, 4, 6, 8, 10, 12, 14]);
+}
+
+#[test]
+fn dag_order_saves_more_rows_on_interleaved_branches() {
+    let initial = interleaved_branches();
+
+    let (id_map, _) = LevelStrategy::new(&[3, 3]).compress(&initial);
+    let (dag_map, _) = LevelStrategy::new(&[3, 3])
+        .with_order(GroupOrder::Dag)
+        .compress(&initial);
+
+    check_that_maps_match(&initial, &id_map);
+    check_that_maps_match(&initial, &dag_map);
+
+    assert!(dag_map.row_count() < id_map.row_count());
+
+    // Every group that isn't a snapshot is based on a group from its own
+    // branch (or the root)
+    for sg in 1i64..=14i64 {
+        if let Some(prev) = dag_map[&sg].prev_state_group {
+            assert!(prev == 0 || prev % 2 == sg % 2);
+        }
+    }
+}
+
+#[test]
+fn dag_order_works_for_compact_maps() {
+    let initial = interleaved_branches();
+    let compact = CompactStateGroupMap::from_map(&initial);
+
+    let mut strategy = LevelStrategy::new(&[3, 3]).with_order(GroupOrder::Dag);
+    let (new_map, _) = strategy.compress(&initial);
+
+    let mut strategy = LevelStrategy::new(&[3, 3]).with_order(GroupOrder::Dag);
+    let (new_compact, _) = strategy.compress_compact(&compact);
+
+    assert_eq!(new_compact.to_map(), new_map);
+}
+
+#[test]
+fn dag_order_works_with_cost_strategy() {
+    let initial = interleaved_branches();
+
+    let (new_map, _) = CostStrategy::new(&[3, 3])
+        .with_order(GroupOrder::Dag)
+        .compress(&initial);
+
+    check_that_maps_match(&initial, &new_map);
+
+    let (id_map, _) = CostStrategy::new(&[3, 3]).compress(&initial);
+    assert!(new_map.row_count() < id_map.row_count());
+}
+
+#[test]
+fn dag_order_works_with_max_depth() {
+    let initial = interleaved_branches();
+
+    let inner = Box::new(LevelStrategy::new(&[20]).with_order(GroupOrder::Dag));
+    let mut strategy = MaxDepthStrategy::new(inner, 3);
+    let (new_map, _) = strategy.compress(&initial);
+
+    check_that_maps_match(&initial, &new_map);
+    assert!(query_cost(&new_map).max <= 3);
+}
@@ -0,0 +1,195 @@
+use crate::{
+    check_that_maps_match,
+    compressor::{
+        depth::depth_of, flatten, CompressionStrategy, Compressor, CompressorOptions, CostStrategy,
+        FlattenMode, Level, LevelStrategy, MaxDepthStrategy,
+    },
+    StateGroupEntry,
+};
+use state_map::StateMap;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Builds the following structure
+///
+/// 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+///
+/// Each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') where j is less than or equal to i
+fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=13i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+fn pinned(groups: &[i64]) -> BTreeSet<i64> {
+    groups.iter().copied().collect()
+}
+
+#[test]
+fn compressor_leaves_pinned_groups_alone() {
+    let initial = line_with_state();
+    let levels = vec![Level::new(3), Level::new(3)];
+
+    let compressor = Compressor::compress_with_options(
+        &initial,
+        &levels,
+        &CompressorOptions {
+            pinned: pinned(&[6, 9]),
+            ..CompressorOptions::default()
+        },
+    );
+    let new_state = &compressor.new_state_group_map;
+
+    // Without pinning, 6 and 9 would be based on 3 and 6. Instead they are
+    // kept as they were. 6's chain is deeper than the levels would have made
+    // it, so it is left out of the levels and 7 isn't based on it. 9's chain
+    // isn't, so it is still placed into the levels:
+    //
+    // 0  3\
+    // 1  4  7\
+    // 2  5  8  10  13
+    //   (6)(9) 11
+    //          12
+    assert_eq!(new_state[&6], initial[&6]);
+    assert_eq!(new_state[&9], initial[&9]);
+    assert_eq!(new_state[&7].prev_state_group, Some(3));
+    assert_eq!(new_state[&10].prev_state_group, Some(7));
+
+    assert_eq!(compressor.stats.pinned_groups, 2);
+
+    check_that_maps_match(&initial, new_state);
+}
+
+#[test]
+fn compressor_pins_nothing_by_default() {
+    let initial = line_with_state();
+    let levels = vec![Level::new(3), Level::new(3)];
+
+    let pinned =
+        Compressor::compress_with_options(&initial, &levels, &CompressorOptions::default());
+    let unpinned = Compressor::compress_from_save(&initial, &levels);
+
+    assert_eq!(pinned.new_state_group_map, unpinned.new_state_group_map);
+    assert_eq!(pinned.stats.pinned_groups, 0);
+}
+
+#[test]
+fn later_groups_can_be_based_on_pinned_heads() {
+    let initial = line_with_state();
+    let levels = vec![Level::new(3), Level::new(3)];
+    let options = CompressorOptions {
+        pinned: pinned(&[7]),
+        ..CompressorOptions::default()
+    };
+
+    let compressor = Compressor::compress_with_options(&initial, &levels, &options);
+    let (cost_state, _) = CostStrategy::new(&[3, 3])
+        .with_options(options)
+        .compress(&initial);
+
+    // 7's chain is no deeper than the levels would have made it, so it is
+    // placed into the levels and 8 is based on it as usual:
+    //
+    // 0  3\
+    // 1  4  6\
+    // 2  5 (7) 9
+    //       8  10
+    //          11
+    for new_state in &[compressor.new_state_group_map, cost_state] {
+        assert_eq!(new_state[&7], initial[&7]);
+        assert_eq!(new_state[&8].prev_state_group, Some(7));
+
+        check_that_maps_match(&initial, new_state);
+    }
+}
+
+#[test]
+fn cost_strategy_leaves_pinned_groups_alone() {
+    let initial = line_with_state();
+
+    let mut strategy = CostStrategy::new(&[3, 3]).with_pinned(pinned(&[6, 9]));
+    let (new_state, stats) = strategy.compress(&initial);
+
+    assert_eq!(new_state[&6], initial[&6]);
+    assert_eq!(new_state[&9], initial[&9]);
+    assert_eq!(stats.pinned_groups, 2);
+
+    check_that_maps_match(&initial, &new_state);
+}
+
+#[test]
+fn pinned_groups_do_not_make_chains_deeper() {
+    let initial = line_with_state();
+    let pinned = pinned(&[6, 9]);
+
+    let compressor = Compressor::compress_with_options(
+        &initial,
+        &[Level::new(3), Level::new(3)],
+        &CompressorOptions {
+            pinned: pinned.clone(),
+            ..CompressorOptions::default()
+        },
+    );
+    let (cost_state, _) = CostStrategy::new(&[3, 3])
+        .with_pinned(pinned.clone())
+        .compress(&initial);
+
+    // No group is any deeper than the levels allow, as the groups are only
+    // based on the pinned groups whose chains are short enough
+    for new_state in &[compressor.new_state_group_map, cost_state] {
+        let mut depths = BTreeMap::new();
+        for sg in new_state.keys() {
+            let depth = depth_of(new_state, &mut depths, *sg);
+            assert!(depth <= 6, "state group {} has depth {}", sg, depth);
+        }
+    }
+}
+
+#[test]
+fn max_depth_strategy_leaves_pinned_groups_alone() {
+    let initial = line_with_state();
+
+    // Levels this big would leave the line as it is
+    let inner = Box::new(LevelStrategy::new(&[20]));
+    let mut strategy = MaxDepthStrategy::new(inner, 5).with_pinned(pinned(&[8]));
+    let (new_state, stats) = strategy.compress(&initial);
+
+    // 8 is left too deep, but the groups after it are still moved
+    assert_eq!(new_state[&8], initial[&8]);
+    assert_ne!(new_state[&9], initial[&9]);
+    assert_eq!(stats.depth_limit_rebases, 8);
+
+    check_that_maps_match(&initial, &new_state);
+}
+
+#[test]
+fn flatten_leaves_pinned_groups_alone() {
+    let initial = line_with_state();
+
+    let (new_state, stats) = flatten(&initial, FlattenMode::Snapshots, &pinned(&[4]));
+
+    assert_eq!(new_state[&4], initial[&4]);
+    assert_eq!(new_state[&5].prev_state_group, None);
+    assert_eq!(stats.pinned_groups, 1);
+    assert_eq!(stats.state_groups_changed, 12);
+
+    check_that_maps_match(&initial, &new_state);
+}
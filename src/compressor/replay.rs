@@ -0,0 +1,76 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resuming an interrupted commit.
+//!
+//! Once some of the changes have been committed the state groups no longer
+//! have their original predecessors, and the strategies that look at those
+//! (such as `--order dag` or the backfill detection) can come up with
+//! different changes for the rest. So instead of compressing the room again,
+//! the plan saved by the interrupted run is applied to the state groups as
+//! they are now.
+
+use std::collections::BTreeMap;
+
+use super::{CompressionStrategy, Level, Stats};
+use crate::StateGroupEntry;
+
+/// Applies a saved plan (the new predecessor and delta of each state group it
+/// changes) to the state groups, so that it can be run in place of a
+/// compression strategy
+///
+/// Only the groups in range are changed. The plan's groups that aren't loaded
+/// are ignored
+pub struct ReplayStrategy {
+    plan: BTreeMap<i64, StateGroupEntry>,
+}
+
+impl ReplayStrategy {
+    /// Creates a strategy that applies the given plan
+    pub fn new(plan: BTreeMap<i64, StateGroupEntry>) -> ReplayStrategy {
+        ReplayStrategy { plan }
+    }
+}
+
+impl CompressionStrategy for ReplayStrategy {
+    fn compress(
+        &mut self,
+        original_state_map: &BTreeMap<i64, StateGroupEntry>,
+    ) -> (BTreeMap<i64, StateGroupEntry>, Stats) {
+        let mut new_state_group_map = original_state_map.clone();
+        let mut stats = Stats::default();
+
+        for (sg, planned) in &self.plan {
+            let entry = match new_state_group_map.get_mut(sg) {
+                Some(entry) if entry.in_range => entry,
+                _ => continue,
+            };
+
+            if entry.prev_state_group != planned.prev_state_group
+                || entry.state_map != planned.state_map
+            {
+                stats.state_groups_changed += 1;
+                entry.prev_state_group = planned.prev_state_group;
+                entry.state_map = planned.state_map.clone();
+            }
+        }
+
+        (new_state_group_map, stats)
+    }
+
+    // There are no levels
+    fn get_level_info(&self) -> Vec<Level> {
+        Vec::new()
+    }
+}
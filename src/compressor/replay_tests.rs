@@ -0,0 +1,101 @@
+use crate::{
+    check_that_maps_match,
+    compressor::{CompressionStrategy, ReplayStrategy},
+    StateGroupEntry,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+
+/// Builds the following structure
+///
+/// 0-1-2-3-4-5
+///
+/// Each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') where j is less than or equal to i
+fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=5i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+/// Returns the entry for group 3 of `line_with_state` stored as a snapshot
+fn group_3_as_snapshot() -> StateGroupEntry {
+    let mut entry = StateGroupEntry {
+        in_range: true,
+        prev_state_group: None,
+        state_map: StateMap::new(),
+    };
+    for j in 0..=3 {
+        entry
+            .state_map
+            .insert("group", &j.to_string(), "seen".into());
+    }
+    entry.state_map.insert("node", "is", "3".into());
+
+    entry
+}
+
+#[test]
+fn replay_applies_the_plan() {
+    let initial = line_with_state();
+
+    let mut plan = BTreeMap::new();
+    plan.insert(3, group_3_as_snapshot());
+
+    let (new_map, stats) = ReplayStrategy::new(plan).compress(&initial);
+
+    assert_eq!(stats.state_groups_changed, 1);
+    assert_eq!(new_map[&3], group_3_as_snapshot());
+    for sg in [0, 1, 2, 4, 5] {
+        assert_eq!(new_map[&sg], initial[&sg]);
+    }
+
+    check_that_maps_match(&initial, &new_map);
+}
+
+#[test]
+fn replay_skips_groups_already_changed_or_not_in_range() {
+    let mut initial = line_with_state();
+
+    // Group 3 was committed before the run was interrupted
+    initial.insert(3, group_3_as_snapshot());
+
+    // Group 1 hasn't been loaded in range
+    initial.get_mut(&1).unwrap().in_range = false;
+
+    let mut plan = BTreeMap::new();
+    plan.insert(3, group_3_as_snapshot());
+    plan.insert(
+        1,
+        StateGroupEntry {
+            in_range: true,
+            prev_state_group: None,
+            state_map: StateMap::new(),
+        },
+    );
+    // Group 9 isn't loaded at all
+    plan.insert(9, group_3_as_snapshot());
+
+    let (new_map, stats) = ReplayStrategy::new(plan).compress(&initial);
+
+    assert_eq!(stats.state_groups_changed, 0);
+    assert_eq!(new_map, initial);
+}
@@ -0,0 +1,142 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storing the full state of a group (a snapshot) at regular intervals.
+//!
+//! Left to themselves the levels only start a new root when they all fill up
+//! (or when no base can be found for a delta), so how much work it takes to
+//! fetch the state of a group depends on the level sizes. A `SnapshotPolicy`
+//! bounds this directly: whenever the chain a new group would be added to has
+//! too many groups, or too many rows of deltas, since its last snapshot, the
+//! new group stores its full state instead. The levels are then reset to start
+//! from it, just as they are when they all fill up.
+//!
+//! The limits are measured along the chain of predecessors in the new tree
+//! (rather than by counting the groups processed), so they hold for chains
+//! that continue on from a previous run of the compressor too.
+
+use std::collections::BTreeMap;
+
+use super::Level;
+use crate::StateGroupMap;
+
+/// When to store the full state of a group rather than a delta (see the module
+/// documentation)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SnapshotPolicy {
+    /// The most groups a chain can have after its last snapshot (including
+    /// the new group). Must be at least 1 if given
+    pub every: Option<usize>,
+    /// The most rows the deltas after the last snapshot of a chain can add up
+    /// to (including the delta of the new group)
+    pub max_delta_rows: Option<usize>,
+}
+
+impl SnapshotPolicy {
+    /// Whether any limits are set
+    pub fn is_enabled(&self) -> bool {
+        self.every.is_some() || self.max_delta_rows.is_some()
+    }
+}
+
+/// Keeps track of how far each chain in the new tree has got since its last
+/// snapshot
+#[derive(Default)]
+pub(super) struct SnapshotTracker {
+    policy: SnapshotPolicy,
+    // The number of groups and rows of deltas after the last snapshot for each
+    // of the groups looked at so far (including the group itself)
+    since_snapshot: BTreeMap<i64, (usize, usize)>,
+}
+
+impl SnapshotTracker {
+    pub fn new(policy: SnapshotPolicy) -> SnapshotTracker {
+        SnapshotTracker {
+            policy,
+            since_snapshot: BTreeMap::new(),
+        }
+    }
+
+    /// Whether a new group with the given predecessor (in the new tree) and
+    /// delta size should be stored as a snapshot instead
+    pub fn needs_snapshot<M: StateGroupMap>(
+        &mut self,
+        new_state_group_map: &M,
+        prev_state_group: Option<i64>,
+        delta_rows: usize,
+    ) -> bool {
+        if !self.policy.is_enabled() {
+            return false;
+        }
+
+        let prev = match prev_state_group {
+            Some(prev) => prev,
+            None => return false,
+        };
+
+        let (groups, rows) = self.since_snapshot_of(new_state_group_map, prev);
+
+        matches!(self.policy.every, Some(every) if groups + 1 > every)
+            || matches!(self.policy.max_delta_rows, Some(max) if rows + delta_rows > max)
+    }
+
+    /// Returns the number of groups and rows of deltas after the last
+    /// snapshot in the chain ending at `state_group`, caching the results
+    fn since_snapshot_of<M: StateGroupMap>(
+        &mut self,
+        new_state_group_map: &M,
+        state_group: i64,
+    ) -> (usize, usize) {
+        // Walk up the chain until we find a group that we already know about
+        // (or we reach the snapshot at the start of the chain)
+        let mut chain = Vec::new();
+        let mut next = Some(state_group);
+        let mut known = (0, 0);
+
+        while let Some(sg) = next {
+            if let Some(k) = self.since_snapshot.get(&sg) {
+                known = *k;
+                break;
+            }
+            next = if new_state_group_map.has_group(sg) {
+                new_state_group_map.group_prev(sg)
+            } else {
+                None
+            };
+            // Snapshots themselves don't count
+            if next.is_some() {
+                chain.push(sg);
+            } else {
+                self.since_snapshot.insert(sg, (0, 0));
+            }
+        }
+
+        // Then fill in the rest on the way back down
+        for sg in chain.into_iter().rev() {
+            let rows = M::state_len(new_state_group_map.group_delta(sg));
+            known = (known.0 + 1, known.1 + rows);
+            self.since_snapshot.insert(sg, known);
+        }
+
+        known
+    }
+}
+
+/// Resets the levels to start new chains at the given snapshot, in the same
+/// way as `place_in_levels` does when all of the levels are full
+pub(super) fn reset_levels(levels: &mut [Level], snapshot: i64) {
+    for level in levels {
+        level.update(snapshot, false);
+    }
+}
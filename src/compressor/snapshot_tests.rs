@@ -0,0 +1,211 @@
+use crate::{
+    check_that_maps_match,
+    compressor::{
+        query_cost, CompressionStrategy, Compressor, CompressorOptions, CostStrategy, Level,
+        LevelStrategy, SnapshotPolicy,
+    },
+    StateGroupEntry,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+
+/// Builds the following structure
+///
+/// 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+///
+/// Each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') where j is less than or equal to i
+///
+/// so every delta after the first is 2 rows
+fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=13i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+/// Returns the groups in the map that have no predecessor
+fn snapshots_in(state_group_map: &BTreeMap<i64, StateGroupEntry>) -> Vec<i64> {
+    state_group_map
+        .iter()
+        .filter(|(_, entry)| entry.prev_state_group.is_none())
+        .map(|(sg, _)| *sg)
+        .collect()
+}
+
+#[test]
+fn snapshot_policy_is_disabled_by_default() {
+    assert!(!SnapshotPolicy::default().is_enabled());
+    assert!(SnapshotPolicy {
+        every: Some(3),
+        max_delta_rows: None,
+    }
+    .is_enabled());
+}
+
+#[test]
+fn default_policy_changes_nothing() {
+    let initial = line_with_state();
+    let levels = vec![Level::new(3), Level::new(3)];
+
+    let without = Compressor::compress_from_save(&initial, &levels);
+    let with = Compressor::compress_with_options(
+        &initial,
+        &levels,
+        &CompressorOptions {
+            snapshot_policy: SnapshotPolicy::default(),
+            ..CompressorOptions::default()
+        },
+    );
+
+    assert_eq!(with.new_state_group_map, without.new_state_group_map);
+    assert_eq!(with.get_level_info(), without.get_level_info());
+    assert_eq!(with.stats.snapshots, 0);
+}
+
+#[test]
+fn snapshot_every_bounds_chain_length() {
+    let initial = line_with_state();
+
+    // A level this big would leave the line as it is
+    let levels = vec![Level::new(20)];
+    let policy = SnapshotPolicy {
+        every: Some(3),
+        max_delta_rows: None,
+    };
+
+    let compressor = Compressor::compress_with_options(
+        &initial,
+        &levels,
+        &CompressorOptions {
+            snapshot_policy: policy,
+            ..CompressorOptions::default()
+        },
+    );
+    let new_state = &compressor.new_state_group_map;
+
+    // This should create the following structure
+    //
+    // 0-1-2-3 4-5-6-7 8-9-10-11 12-13
+    assert_eq!(snapshots_in(new_state), vec![0, 4, 8, 12]);
+    assert_eq!(compressor.stats.snapshots, 3);
+    assert_eq!(compressor.stats.state_groups_changed, 3);
+    assert_eq!(compressor.stats.resets_no_suitable_prev, 0);
+    assert_eq!(query_cost(new_state).max, 4);
+
+    // The levels start again from the last snapshot
+    assert_eq!(
+        compressor.get_level_info(),
+        vec![Level::restore(20, 2, Some(13))]
+    );
+
+    check_that_maps_match(&initial, new_state);
+}
+
+#[test]
+fn snapshot_rows_bounds_delta_size() {
+    let initial = line_with_state();
+
+    let levels = vec![Level::new(20)];
+    let policy = SnapshotPolicy {
+        every: None,
+        max_delta_rows: Some(5),
+    };
+
+    let compressor = Compressor::compress_with_options(
+        &initial,
+        &levels,
+        &CompressorOptions {
+            snapshot_policy: policy,
+            ..CompressorOptions::default()
+        },
+    );
+    let new_state = &compressor.new_state_group_map;
+
+    // Only two deltas of 2 rows fit in each chain
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    assert_eq!(snapshots_in(new_state), vec![0, 3, 6, 9, 12]);
+    assert_eq!(compressor.stats.snapshots, 4);
+
+    check_that_maps_match(&initial, new_state);
+}
+
+#[test]
+fn snapshot_policy_counts_chains_from_earlier_runs() {
+    let initial = line_with_state();
+    let policy = SnapshotPolicy {
+        every: Some(3),
+        max_delta_rows: None,
+    };
+
+    // Compress the first half of the line
+    let first_half: BTreeMap<i64, StateGroupEntry> = initial
+        .iter()
+        .filter(|(sg, _)| **sg <= 6)
+        .map(|(sg, entry)| (*sg, entry.clone()))
+        .collect();
+
+    let mut strategy = LevelStrategy::new(&[20]).with_snapshot_policy(policy);
+    let (first_new, _) = strategy.compress(&first_half);
+
+    assert_eq!(snapshots_in(&first_new), vec![0, 4]);
+
+    // Then carry on with the second half, with the first half as it was left
+    let mut second_half: BTreeMap<i64, StateGroupEntry> = first_new
+        .into_iter()
+        .map(|(sg, mut entry)| {
+            entry.in_range = false;
+            (sg, entry)
+        })
+        .collect();
+    second_half.extend(
+        initial
+            .iter()
+            .filter(|(sg, _)| **sg > 6)
+            .map(|(sg, entry)| (*sg, entry.clone())),
+    );
+
+    let (second_new, stats) = strategy.compress(&second_half);
+
+    // 4-5-6 are carried over, so 7 is the last group in that chain
+    assert_eq!(snapshots_in(&second_new), vec![0, 4, 8, 12]);
+    assert_eq!(stats.snapshots, 2);
+    assert_eq!(query_cost(&second_new).max, 4);
+}
+
+#[test]
+fn cost_strategy_follows_snapshot_policy() {
+    let initial = line_with_state();
+    let policy = SnapshotPolicy {
+        every: Some(3),
+        max_delta_rows: None,
+    };
+
+    let mut strategy = CostStrategy::new(&[20]).with_snapshot_policy(policy);
+    let (new_state, stats) = strategy.compress(&initial);
+
+    assert_eq!(snapshots_in(&new_state), vec![0, 4, 8, 12]);
+    assert_eq!(stats.snapshots, 3);
+    assert_eq!(stats.resets_no_suitable_prev, 0);
+    assert!(query_cost(&new_state).max <= 4);
+
+    check_that_maps_match(&initial, &new_state);
+}
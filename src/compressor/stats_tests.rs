@@ -1,9 +1,12 @@
 use crate::{
-    compressor::{Compressor, Level, Stats},
+    compressor::{
+        backfill::BackfillDetector, snapshot::SnapshotTracker, Compressor, GroupOrder, Level, Stats,
+    },
+    state_cache::StateCache,
     StateGroupEntry,
 };
 use state_map::StateMap;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 #[test]
 fn stats_correct_when_no_resets() {
@@ -28,8 +31,13 @@ fn stats_correct_when_no_resets() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
         stats: Stats::default(),
     };
 
@@ -85,8 +93,13 @@ fn stats_correct_when_some_resets() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
         stats: Stats::default(),
     };
 
@@ -102,15 +115,18 @@ fn stats_correct_when_some_resets() {
     //          11
     compressor.create_new_tree();
 
-    // the reset required for 4 contributes 2 to the size stat
-    // - (1 'node' and 1 'group') entry
-    // the reset required for 6 contributes 4 to the size stat
-    // - (1 'node' and 3 'group') entry
+    // the resets required for 4 and 6 contribute 2 and 4 to the size stat
+    // - (1 'node' and 1 'group') entry for 4
+    // - (1 'node' and 3 'group') entries for 6
     assert_eq!(compressor.stats.resets_no_suitable_prev, 2);
     assert_eq!(compressor.stats.resets_no_suitable_prev_size, 6);
 
-    // groups 3,4,6,9,12 are the only ones changed
-    assert_eq!(compressor.stats.state_groups_changed, 5);
+    // 6 can't use 3, and its original predecessor 5 is deeper than 3
+    assert_eq!(compressor.stats.resets_avoided, 0);
+
+    // groups 3,6,9,12 are the only ones changed (4 had no predecessor to
+    // begin with so is left as it was)
+    assert_eq!(compressor.stats.state_groups_changed, 4);
 }
 
 #[test]
@@ -164,8 +180,13 @@ fn stats_correct_if_no_changes() {
 
     let mut compressor = Compressor {
         original_state_map: &initial,
+        state_cache: StateCache::new(&initial),
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
+        order: GroupOrder::Id,
+        snapshots: SnapshotTracker::default(),
+        pinned: BTreeSet::new(),
+        backfill: BackfillDetector::new(false, GroupOrder::Id),
         stats: Stats::default(),
     };
 
@@ -0,0 +1,161 @@
+use crate::{
+    compressor::{
+        CompressionStrategy, Compressor, CompressorOptions, CostStrategy, Level, LevelStrategy,
+        Strategy,
+    },
+    CompactStateGroupMap, StateGroupEntry,
+};
+use state_map::StateMap;
+use std::{collections::BTreeMap, str::FromStr};
+
+#[test]
+fn level_strategy_matches_compressor() {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    // This starts with the following structure
+    //
+    // 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') where j is less than i
+    for i in 0i64..=13i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    let compressor = Compressor::compress(&initial, &[3, 3]);
+
+    let mut strategy = LevelStrategy::new(&[3, 3]);
+    let (new_state_group_map, stats) = strategy.compress(&initial);
+
+    assert_eq!(new_state_group_map, compressor.new_state_group_map);
+    assert_eq!(
+        stats.state_groups_changed,
+        compressor.stats.state_groups_changed
+    );
+    assert_eq!(strategy.get_level_info(), compressor.get_level_info());
+}
+
+#[test]
+fn level_strategy_remembers_levels_between_runs() {
+    let mut strategy = LevelStrategy::new(&[3, 3]);
+    assert_eq!(
+        strategy.get_level_info(),
+        vec![Level::new(3), Level::new(3)]
+    );
+
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    // 0-1-2-3-4-5-6
+    for i in 0i64..=6i64 {
+        initial.insert(
+            i,
+            StateGroupEntry {
+                in_range: true,
+                prev_state_group: prev,
+                state_map: StateMap::new(),
+            },
+        );
+
+        prev = Some(i)
+    }
+
+    strategy.compress(&initial);
+
+    // This should create the following structure
+    //
+    // 0  3\
+    // 1  4 6
+    // 2  5
+    assert_eq!(
+        strategy.get_level_info(),
+        vec![Level::restore(3, 1, Some(6)), Level::restore(3, 2, Some(6))]
+    );
+}
+
+#[test]
+fn compress_compact_matches_compress() {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    // 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+    //
+    // with 0-3 out of range, and each group i having state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') where j is less than i
+    for i in 0i64..=13i64 {
+        let mut entry = StateGroupEntry {
+            in_range: i > 3,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    let compact = CompactStateGroupMap::from_map(&initial);
+
+    // LevelStrategy compresses the compact map directly whereas CostStrategy
+    // uses the default implementation
+    let strategies: [(Box<dyn CompressionStrategy>, Box<dyn CompressionStrategy>); 2] = [
+        (
+            Box::new(LevelStrategy::new(&[3, 3])),
+            Box::new(LevelStrategy::new(&[3, 3])),
+        ),
+        (
+            Box::new(CostStrategy::new(&[3, 3])),
+            Box::new(CostStrategy::new(&[3, 3])),
+        ),
+    ];
+
+    for (mut strategy, mut compact_strategy) in IntoIterator::into_iter(strategies) {
+        let (new_state_group_map, stats) = strategy.compress(&initial);
+        let (new_compact_map, compact_stats) = compact_strategy.compress_compact(&compact);
+
+        assert_eq!(new_compact_map.to_map(), new_state_group_map);
+        assert_eq!(
+            compact_stats.state_groups_changed,
+            stats.state_groups_changed
+        );
+        assert_eq!(compact_strategy.get_level_info(), strategy.get_level_info());
+    }
+}
+
+#[test]
+fn strategy_from_str_parses_level() {
+    assert_eq!(Strategy::from_str("level"), Ok(Strategy::Level));
+    assert_eq!(Strategy::default(), Strategy::Level);
+}
+
+#[test]
+fn strategy_from_str_produces_err_if_unknown() {
+    assert!(Strategy::from_str("sheep").is_err());
+}
+
+#[test]
+fn strategy_build_restores_levels() {
+    let level_info = vec![Level::restore(3, 1, Some(6)), Level::restore(3, 2, Some(6))];
+    let strategy = Strategy::Level.build(&level_info, &CompressorOptions::default());
+
+    assert_eq!(strategy.get_level_info(), level_info);
+}
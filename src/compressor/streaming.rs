@@ -0,0 +1,314 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The level algorithm, one state group at a time.
+//!
+//! `Compressor` needs every state group in the range (and all of their
+//! predecessors) to be loaded before it starts. `StreamingCompressor` is
+//! instead given the state groups one by one in order of ID, and works out the
+//! new entry for each of them straight away. The only states it holds on to are
+//! the full states of the current heads of the levels, as those are the groups
+//! the next ones will be based on. Any other state it needs (e.g. that of an
+//! original predecessor from before the range) is asked for through a lookup
+//! function.
+//!
+//! As the states of the rest of the new tree aren't kept, it can't walk back
+//! up the tree when the suggested predecessor isn't a valid base like
+//! `Compressor` does. It goes straight on to trying the other level heads and
+//! the original predecessor, so it can end up storing the full state slightly
+//! more often.
+//!
+//! Like `Compressor` it only falls back to bases whose chain is no deeper than
+//! that of the suggested predecessor. The depths are only known for the level
+//! heads it has placed itself, so any other base (e.g. an original predecessor
+//! that is no longer a head, or a head restored from a previous run) isn't
+//! used.
+
+use state_map::StateMap;
+use std::collections::HashMap;
+use string_cache::DefaultAtom as Atom;
+
+use super::{
+    backfill::BackfillDetector, calculate_delta, place_in_levels, GroupOrder, Level,
+    StateGroupEntry, Stats,
+};
+
+/// Compresses state groups one at a time (see the module documentation)
+pub struct StreamingCompressor {
+    levels: Vec<Level>,
+    // The full states of the level heads that have been worked out so far
+    head_states: HashMap<i64, StateMap<Atom>>,
+    // The depths of the level heads that have been placed by this compressor
+    head_depths: HashMap<i64, usize>,
+    // The last state that had to be looked up, as it is often needed again
+    // straight away (e.g. when checking the new entry)
+    last_lookup: Option<(i64, StateMap<Atom>)>,
+    backfill: BackfillDetector,
+    pub stats: Stats,
+}
+
+impl StreamingCompressor {
+    /// Creates a compressor with empty levels of the given maximum sizes
+    pub fn new(level_sizes: &[usize]) -> StreamingCompressor {
+        let levels: Vec<Level> = level_sizes.iter().map(|size| Level::new(*size)).collect();
+        StreamingCompressor::from_save(&levels)
+    }
+
+    /// Creates a compressor that continues on from the level state saved by a
+    /// previous run. The states of the heads are looked up when first needed
+    pub fn from_save(level_info: &[Level]) -> StreamingCompressor {
+        StreamingCompressor {
+            levels: level_info.to_vec(),
+            head_states: HashMap::new(),
+            head_depths: HashMap::new(),
+            last_lookup: None,
+            backfill: BackfillDetector::new(false, GroupOrder::Id),
+            stats: Stats::default(),
+        }
+    }
+
+    /// Sets whether groups that look like they were backfilled should be left
+    /// as they are
+    pub fn with_skip_backfill(mut self, skip_backfill: bool) -> StreamingCompressor {
+        self.backfill = BackfillDetector::new(skip_backfill, GroupOrder::Id);
+        self
+    }
+
+    /// Returns the current state of the levels
+    pub fn get_level_info(&self) -> Vec<Level> {
+        self.levels.clone()
+    }
+
+    /// Works out the new entry for the next state group. The groups must be
+    /// given in ascending order, and are all treated as being in range
+    ///
+    /// If `with_skip_backfill` was used then groups that look like they were
+    /// backfilled are returned unchanged
+    ///
+    /// Panics if the state of the new entry doesn't match the original one
+    ///
+    /// # Arguments
+    ///
+    /// * `state_group`         -   The ID of the state group
+    /// * `prev_state_group`    -   Its predecessor in the database
+    /// * `delta`               -   Its delta from that predecessor in the database
+    /// * `lookup`              -   Gets the full state of a group that came before
+    ///                             this one
+    pub fn add_group<F>(
+        &mut self,
+        state_group: i64,
+        prev_state_group: Option<i64>,
+        delta: StateMap<Atom>,
+        mut lookup: F,
+    ) -> StateGroupEntry
+    where
+        F: FnMut(i64) -> StateMap<Atom>,
+    {
+        if self.backfill.is_backfill(&self.levels, prev_state_group) {
+            self.backfill.add_backfilled(state_group);
+            self.stats.backfill_groups += 1;
+
+            return StateGroupEntry {
+                in_range: true,
+                prev_state_group,
+                state_map: delta,
+            };
+        }
+
+        let mut state_map = match prev_state_group {
+            Some(prev) => self.state_of(prev, &mut lookup),
+            None => StateMap::new(),
+        };
+        state_map.extend(delta.iter().map(|((t, s), e)| ((t, s), e.clone())));
+
+        let suggested_prev = place_in_levels(&mut self.levels, state_group);
+        self.backfill.add_placed(state_group);
+
+        let entry = if suggested_prev == prev_state_group {
+            StateGroupEntry {
+                in_range: true,
+                prev_state_group,
+                state_map: delta,
+            }
+        } else {
+            let (new_delta, new_prev) = self.get_delta(
+                state_group,
+                suggested_prev,
+                prev_state_group,
+                &state_map,
+                &mut lookup,
+            );
+
+            if new_prev == prev_state_group {
+                // We've ended up back at the original predecessor, so there
+                // is no need to change this group after all
+                StateGroupEntry {
+                    in_range: true,
+                    prev_state_group,
+                    state_map: delta,
+                }
+            } else {
+                self.stats.state_groups_changed += 1;
+                StateGroupEntry {
+                    in_range: true,
+                    prev_state_group: new_prev,
+                    state_map: new_delta,
+                }
+            }
+        };
+
+        // Check that the new entry gives the same state as the original one
+        let mut new_state_map = match entry.prev_state_group {
+            Some(prev) => self.state_of(prev, &mut lookup),
+            None => StateMap::new(),
+        };
+        new_state_map.extend(
+            entry
+                .state_map
+                .iter()
+                .map(|((t, s), e)| ((t, s), e.clone())),
+        );
+        if new_state_map != state_map {
+            panic!(
+                "States for group {} do not match. Expected {:#?}, found {:#?}",
+                state_group, state_map, new_state_map
+            );
+        }
+
+        let depth = match entry.prev_state_group {
+            Some(prev) => self.head_depths.get(&prev).map(|depth| depth + 1),
+            None => Some(1),
+        };
+
+        // Only hold on to the states and depths of the groups that are still
+        // heads
+        self.head_states.insert(state_group, state_map);
+        self.head_depths
+            .extend(depth.map(|depth| (state_group, depth)));
+        let levels = &self.levels;
+        self.head_states
+            .retain(|sg, _| levels.iter().any(|l| l.get_head() == Some(*sg)));
+        self.head_depths
+            .retain(|sg, _| levels.iter().any(|l| l.get_head() == Some(*sg)));
+
+        entry
+    }
+
+    /// Finds a base for the delta of a group whose suggested predecessor is
+    /// different to its original one. The suggested predecessor is tried
+    /// first, then the smallest delta from the other level heads and the
+    /// original predecessor is used, as long as its chain is known to be no
+    /// deeper than that of the suggested predecessor
+    ///
+    /// Returns the delta and the base it is from (if any)
+    fn get_delta<F>(
+        &mut self,
+        state_group: i64,
+        suggested_prev: Option<i64>,
+        original_prev: Option<i64>,
+        state_map: &StateMap<Atom>,
+        lookup: &mut F,
+    ) -> (StateMap<Atom>, Option<i64>)
+    where
+        F: FnMut(i64) -> StateMap<Atom>,
+    {
+        let suggested_prev = match suggested_prev {
+            Some(prev) => prev,
+            None => return (state_map.clone(), None),
+        };
+
+        let prev_state_map = self.state_of(suggested_prev, lookup);
+        if let Some(delta_map) = calculate_delta(&prev_state_map, state_map) {
+            return (delta_map, Some(suggested_prev));
+        }
+
+        // Some of the levels will already have this group as their head
+        let mut candidates: Vec<i64> = self
+            .levels
+            .iter()
+            .filter_map(|l| l.get_head())
+            .filter(|head| *head != suggested_prev && *head != state_group)
+            .collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates.extend(original_prev);
+
+        let max_depth = self.head_depths.get(&suggested_prev).copied();
+        let head_depths = &self.head_depths;
+        candidates.retain(|candidate| match (head_depths.get(candidate), max_depth) {
+            (Some(depth), Some(max_depth)) => *depth <= max_depth,
+            _ => false,
+        });
+
+        let mut best: Option<(StateMap<Atom>, i64)> = None;
+
+        for candidate in candidates {
+            let prev_state_map = self.state_of(candidate, lookup);
+            if let Some(delta_map) = calculate_delta(&prev_state_map, state_map) {
+                let is_smaller = match &best {
+                    Some((best_delta, _)) => delta_map.len() < best_delta.len(),
+                    None => true,
+                };
+                if is_smaller {
+                    best = Some((delta_map, candidate));
+                }
+            }
+        }
+
+        if let Some((delta_map, base_sg)) = best {
+            self.stats.resets_avoided += 1;
+            return (delta_map, Some(base_sg));
+        }
+
+        // Couldn't find a new base, so we give up and just persist
+        // a full state group here.
+        self.stats.resets_no_suitable_prev += 1;
+        self.stats.resets_no_suitable_prev_size += state_map.len();
+
+        (state_map.clone(), None)
+    }
+
+    /// Gets the full state of a group, from the level heads if it is one of
+    /// them or through `lookup` if not
+    fn state_of<F>(&mut self, state_group: i64, lookup: &mut F) -> StateMap<Atom>
+    where
+        F: FnMut(i64) -> StateMap<Atom>,
+    {
+        if let Some(state_map) = self.head_states.get(&state_group) {
+            return state_map.clone();
+        }
+
+        if let Some((sg, state_map)) = &self.last_lookup {
+            if *sg == state_group {
+                return state_map.clone();
+            }
+        }
+
+        let state_map = lookup(state_group);
+
+        // Heads restored from a previous run are looked up the first time
+        // they're used, so remember them for next time
+        if self
+            .levels
+            .iter()
+            .any(|l| l.get_head() == Some(state_group))
+        {
+            self.head_states.insert(state_group, state_map.clone());
+        } else {
+            self.last_lookup = Some((state_group, state_map.clone()));
+        }
+
+        state_map
+    }
+}
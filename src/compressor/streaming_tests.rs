@@ -0,0 +1,194 @@
+use crate::{
+    collapse_state_maps,
+    compressor::{CompressionStrategy, Compressor, Level, LevelStrategy, StreamingCompressor},
+    StateGroupEntry,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+
+/// Builds the following structure
+///
+/// 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+///
+/// Each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') where j is less than or equal to i
+fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=13i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+/// Passes the groups of `initial` that are in range to the compressor in
+/// order, copying the others across unchanged
+///
+/// Returns the new map and the groups that had to be looked up
+fn stream(
+    compressor: &mut StreamingCompressor,
+    initial: &BTreeMap<i64, StateGroupEntry>,
+) -> (BTreeMap<i64, StateGroupEntry>, Vec<i64>) {
+    let mut new_map = BTreeMap::new();
+    let mut lookups = Vec::new();
+
+    for (sg, entry) in initial {
+        if !entry.in_range {
+            new_map.insert(*sg, entry.clone());
+            continue;
+        }
+
+        let new_entry = compressor.add_group(
+            *sg,
+            entry.prev_state_group,
+            entry.state_map.clone(),
+            |prev| {
+                lookups.push(prev);
+                collapse_state_maps(initial, prev)
+            },
+        );
+        new_map.insert(*sg, new_entry);
+    }
+
+    (new_map, lookups)
+}
+
+#[test]
+fn streaming_matches_compressor() {
+    let initial = line_with_state();
+
+    let compressor = Compressor::compress(&initial, &[3, 3]);
+
+    let mut streaming = StreamingCompressor::new(&[3, 3]);
+    let (new_state_group_map, lookups) = stream(&mut streaming, &initial);
+
+    assert_eq!(new_state_group_map, compressor.new_state_group_map);
+    assert_eq!(
+        streaming.stats.state_groups_changed,
+        compressor.stats.state_groups_changed
+    );
+    assert_eq!(streaming.get_level_info(), compressor.get_level_info());
+
+    // Every predecessor is the previous group, which is always a level head
+    assert!(lookups.is_empty());
+}
+
+#[test]
+fn streaming_looks_up_restored_heads() {
+    let mut initial = line_with_state();
+
+    for i in 0i64..=9i64 {
+        initial.get_mut(&i).unwrap().in_range = false;
+    }
+
+    // Continue on from 9 as if 0-9 had been compressed in an earlier run
+    let level_info = vec![Level::restore(3, 1, Some(9)), Level::restore(3, 1, Some(6))];
+
+    let (level_map, _) = LevelStrategy::from_save(&level_info).compress(&initial);
+
+    let mut streaming = StreamingCompressor::from_save(&level_info);
+    let (new_state_group_map, lookups) = stream(&mut streaming, &initial);
+
+    assert_eq!(new_state_group_map, level_map);
+
+    // The states of the restored heads are looked up when they are first
+    // needed: 9 as the predecessor of 10, and 6 as the base for 12
+    assert_eq!(lookups, vec![9, 6]);
+}
+
+#[test]
+fn streaming_only_falls_back_to_bases_no_deeper_than_suggested() {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    // This starts with the following structure
+    //
+    // (note missing 3-4 link)
+    // 0-1-2-3
+    // 4-5-6
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') where j is ancestor of i
+    for i in 0i64..=6i64 {
+        if i == 4 {
+            prev = None
+        }
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    let compressor = Compressor::compress(&initial, &[3, 3]);
+
+    let mut streaming = StreamingCompressor::new(&[3, 3]);
+    let (new_state_group_map, _) = stream(&mut streaming, &initial);
+
+    // The levels suggest 3 for both 4 and 6, which has keys neither of them
+    // have. 4 has to store its full state (as it did before), and so does 6
+    // as its original predecessor 5 is deeper than 3
+    //
+    // 0  3  4
+    // 1     5
+    // 2     6
+    assert_eq!(new_state_group_map[&4].prev_state_group, None);
+    assert_eq!(new_state_group_map[&6].prev_state_group, None);
+    assert_eq!(streaming.stats.resets_no_suitable_prev, 2);
+    assert_eq!(streaming.stats.resets_avoided, 0);
+
+    assert_eq!(new_state_group_map, compressor.new_state_group_map);
+}
+
+#[test]
+fn streaming_falls_back_to_original_predecessor() {
+    let mut initial = line_with_state();
+
+    // Base 8 on 6 instead of 7
+    //
+    // 0-1-2-3-4-5-6-7
+    //              \
+    //               8-9-10-11-12-13
+    initial.get_mut(&8).unwrap().prev_state_group = Some(6);
+
+    let compressor = Compressor::compress(&initial, &[3, 3]);
+
+    let mut streaming = StreamingCompressor::new(&[3, 3]);
+    let (new_state_group_map, _) = stream(&mut streaming, &initial);
+
+    // The levels suggest 7 for 8, which has a key 8 doesn't have. 8 can stay
+    // based on 6 as that is the other level head and is less deep than 7
+    //
+    // 0  3\
+    // 1  4 6
+    // 2  5 7
+    //      8
+    assert_eq!(new_state_group_map[&8], initial[&8]);
+    assert_eq!(streaming.stats.resets_avoided, 1);
+
+    assert_eq!(new_state_group_map, compressor.new_state_group_map);
+}
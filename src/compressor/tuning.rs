@@ -0,0 +1,134 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Working out which level sizes to use for a room.
+//!
+//! The level algorithm is run over the same state groups once for each of a
+//! set of candidate level sizes, and the results are compared. Bigger levels
+//! save more rows but leave longer chains of predecessors, which makes the
+//! state slower to fetch, so the recommendation is the candidate that saves
+//! the most rows without any chain going over a depth budget.
+
+use super::{query_cost, Compressor, Level, StateGroupMap};
+
+/// How the level algorithm got on with one set of level sizes
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuningResult {
+    /// The sizes of the levels that were used
+    pub level_sizes: Vec<usize>,
+    /// How many rows fewer the compressed state groups take up (this is
+    /// negative if compressing would add rows)
+    pub rows_saved: i64,
+    /// How many state groups were changed
+    pub state_groups_changed: usize,
+    /// How many state groups had to store their full state because no
+    /// suitable predecessor was found
+    pub resets: usize,
+    /// The longest chain of predecessors of a group in range after compressing
+    pub max_depth: usize,
+    /// The average length of the chains of predecessors of the groups in
+    /// range after compressing
+    pub mean_depth: f64,
+}
+
+/// The results of trying each of the candidate level sizes
+#[derive(Debug, Clone, PartialEq)]
+pub struct TuningReport {
+    /// The result for each candidate, in the order they were given
+    pub results: Vec<TuningResult>,
+    /// The index in `results` of the recommended candidate, if any of them
+    /// stay within the depth budget
+    pub best: Option<usize>,
+}
+
+impl TuningReport {
+    /// Returns the result for the recommended candidate
+    pub fn recommended(&self) -> Option<&TuningResult> {
+        self.best.map(|i| &self.results[i])
+    }
+}
+
+/// The level sizes tried when none are given: one to three levels, with the
+/// lowest level holding 25 to 200 groups and each higher level either half the
+/// size of the one below it or the same size
+pub fn default_candidates() -> Vec<Vec<usize>> {
+    let mut candidates = Vec::new();
+
+    for lowest in &[25, 50, 100, 200] {
+        candidates.push(vec![*lowest]);
+        for num_levels in 2..=3 {
+            candidates.push((0..num_levels).map(|i| (lowest >> i).max(1)).collect());
+            candidates.push(vec![*lowest; num_levels]);
+        }
+    }
+
+    candidates
+}
+
+/// Runs the level algorithm over `state_group_map` with the given level sizes
+/// and measures the result
+pub fn evaluate<M: StateGroupMap>(state_group_map: &M, level_sizes: &[usize]) -> TuningResult {
+    let levels: Vec<Level> = level_sizes.iter().map(|size| Level::new(*size)).collect();
+    let compressor = Compressor::compress_from_save(state_group_map, &levels);
+    let new_state_group_map = &compressor.new_state_group_map;
+
+    let cost = query_cost(new_state_group_map);
+
+    TuningResult {
+        level_sizes: level_sizes.to_vec(),
+        rows_saved: state_group_map.row_count() as i64 - new_state_group_map.row_count() as i64,
+        state_groups_changed: compressor.stats.state_groups_changed,
+        resets: compressor.stats.resets_no_suitable_prev,
+        max_depth: cost.max,
+        mean_depth: cost.mean,
+    }
+}
+
+/// Tries each of the candidate level sizes on `state_group_map` and picks the
+/// best one
+///
+/// The recommended candidate is the one that saves the most rows out of those
+/// whose chains all stay within `depth_budget`. Ties go to the one with the
+/// shortest chains, and then the one that changes the fewest state groups.
+///
+/// # Arguments
+///
+/// * `state_group_map` -   The state groups to compress (these are not changed)
+/// * `candidates`      -   The level sizes to try
+/// * `depth_budget`    -   The longest chain of predecessors allowed (if any)
+pub fn tune<M: StateGroupMap>(
+    state_group_map: &M,
+    candidates: &[Vec<usize>],
+    depth_budget: Option<usize>,
+) -> TuningReport {
+    let results: Vec<TuningResult> = candidates
+        .iter()
+        .map(|level_sizes| evaluate(state_group_map, level_sizes))
+        .collect();
+
+    let best = results
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| !matches!(depth_budget, Some(budget) if r.max_depth > budget))
+        // min_by picks the earliest candidate if they are otherwise equal
+        .min_by(|(_, a), (_, b)| {
+            b.rows_saved
+                .cmp(&a.rows_saved)
+                .then(a.max_depth.cmp(&b.max_depth))
+                .then(a.state_groups_changed.cmp(&b.state_groups_changed))
+        })
+        .map(|(i, _)| i);
+
+    TuningReport { results, best }
+}
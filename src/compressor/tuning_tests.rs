@@ -0,0 +1,119 @@
+use crate::{
+    compressor::{default_candidates, depth_histogram, evaluate, tune, Compressor},
+    StateGroupEntry, StateGroupMap,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+
+/// Builds the following structure
+///
+/// 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+///
+/// Each group i has state:
+///     ('node','is',      i)
+///     ('group',  j, 'seen') where j is less than or equal to i
+fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+
+    for i in 0i64..=13i64 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        entry.state_map.insert("node", "is", i.to_string().into());
+
+        initial.insert(i, entry);
+
+        prev = Some(i)
+    }
+
+    initial
+}
+
+#[test]
+fn evaluate_matches_compressor() {
+    let initial = line_with_state();
+
+    let result = evaluate(&initial, &[3, 3]);
+
+    let compressor = Compressor::compress(&initial, &[3, 3]);
+    let new_map = &compressor.new_state_group_map;
+    let histogram = depth_histogram(new_map);
+
+    assert_eq!(result.level_sizes, vec![3, 3]);
+    assert_eq!(
+        result.rows_saved,
+        initial.row_count() as i64 - new_map.row_count() as i64
+    );
+    assert_eq!(
+        result.state_groups_changed,
+        compressor.stats.state_groups_changed
+    );
+    assert_eq!(result.resets, compressor.stats.resets_no_suitable_prev);
+    assert_eq!(result.max_depth, *histogram.keys().last().unwrap());
+}
+
+#[test]
+fn evaluate_leaves_line_alone_if_levels_big_enough() {
+    let initial = line_with_state();
+
+    let result = evaluate(&initial, &[20]);
+
+    assert_eq!(result.rows_saved, 0);
+    assert_eq!(result.state_groups_changed, 0);
+    assert_eq!(result.max_depth, 14);
+    assert!((result.mean_depth - 7.5).abs() < 1e-9);
+}
+
+#[test]
+fn tune_picks_most_rows_saved_within_budget() {
+    let initial = line_with_state();
+    let candidates = vec![vec![20], vec![3, 3], vec![2, 2, 2]];
+
+    // Without a budget the line is best left alone, as any other tree has
+    // bigger deltas
+    let report = tune(&initial, &candidates, None);
+    assert_eq!(report.results.len(), 3);
+    assert_eq!(report.recommended().unwrap().level_sizes, vec![20]);
+
+    // But a chain of 14 groups is too deep for this budget
+    let report = tune(&initial, &candidates, Some(6));
+    let best = report.recommended().unwrap();
+    assert!(best.max_depth <= 6);
+    for result in &report.results {
+        if result.max_depth <= 6 {
+            assert!(result.rows_saved <= best.rows_saved);
+        }
+    }
+
+    // And nothing fits in this one
+    let report = tune(&initial, &candidates, Some(1));
+    assert_eq!(report.best, None);
+}
+
+#[test]
+fn tune_doesnt_change_map() {
+    let initial = line_with_state();
+    let copy = initial.clone();
+
+    tune(&initial, &[vec![3, 3]], None);
+
+    assert_eq!(initial, copy);
+}
+
+#[test]
+fn default_candidates_include_default_level_sizes() {
+    let candidates = default_candidates();
+
+    assert!(candidates.contains(&vec![100, 50, 25]));
+
+    let mut deduped = candidates.clone();
+    deduped.sort();
+    deduped.dedup();
+    assert_eq!(deduped.len(), candidates.len());
+}
@@ -0,0 +1,252 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Loading the state groups of a room with `COPY ... TO STDOUT`.
+//!
+//! For rooms with millions of rows most of the time spent loading goes on the
+//! join in `INITIAL_DATA_SQL` and on decoding its rows. `CopyLoader` instead
+//! copies out the room's groups (with their predecessors) and the rows of
+//! their deltas separately, in Postgres' binary format, and joins them in
+//! memory. The rows it passes on are the same as those of the query, so the
+//! state groups loaded through it are the same too.
+
+use std::collections::BTreeMap;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use log::debug;
+use postgres::{
+    binary_copy::{BinaryCopyOutIter, BinaryCopyOutRow},
+    fallible_iterator::FallibleIterator,
+    types::Type,
+    Client,
+};
+
+use crate::{database::PGEscape, StateGroupEntry, StateRow, StateStore};
+
+/// Loads the state groups in a range with `COPY`, and does everything else
+/// through the client in the same way as `Client` itself
+pub struct CopyLoader<'a> {
+    // The client the state groups are loaded over
+    client: &'a mut Client,
+}
+
+impl<'a> CopyLoader<'a> {
+    /// Loads the state groups over the client given
+    pub fn new(client: &'a mut Client) -> CopyLoader<'a> {
+        CopyLoader { client }
+    }
+}
+
+impl StateStore for CopyLoader<'_> {
+    fn find_max_group(
+        &mut self,
+        room_id: &str,
+        min_state_group: Option<i64>,
+        groups_to_compress: Option<i64>,
+        max_state_group: Option<i64>,
+    ) -> Option<i64> {
+        self.client.find_max_group(
+            room_id,
+            min_state_group,
+            groups_to_compress,
+            max_state_group,
+        )
+    }
+
+    fn fetch_groups_in_range(
+        &mut self,
+        room_id: &str,
+        min_state_group: Option<i64>,
+        max_state_group: i64,
+        f: &mut dyn FnMut(StateRow<'_>),
+    ) {
+        let condition = range_condition(room_id, min_state_group, max_state_group);
+
+        // The predecessor of each group, and whether any rows of its delta
+        // have been passed on yet
+        let mut groups: BTreeMap<i64, (Option<i64>, bool)> = BTreeMap::new();
+
+        // Nothing is passed on until the deltas are copied, so the groups get
+        // a spinner of their own. The rows of the deltas are counted by the
+        // spinner of whatever is loading them
+        let pb: ProgressBar;
+        if cfg!(feature = "no-progress-bars") {
+            pb = ProgressBar::hidden();
+        } else {
+            pb = ProgressBar::new_spinner();
+        }
+        pb.set_style(
+            ProgressStyle::default_spinner()
+                .template("{spinner} [{elapsed}] {pos} state groups copied"),
+        );
+        pb.enable_steady_tick(100);
+
+        copy_rows(
+            self.client,
+            &format!("{} WHERE {}", COPY_GROUPS_SQL, condition),
+            &[Type::INT8, Type::INT8],
+            |row| {
+                groups.insert(row.get(0), (row.get(1), false));
+                pb.inc(1);
+            },
+        );
+
+        pb.set_length(pb.position());
+        pb.finish();
+
+        debug!(
+            "Copied {} state groups, copying their deltas...",
+            groups.len()
+        );
+
+        copy_rows(
+            self.client,
+            &format!("{} WHERE {}", COPY_DELTAS_SQL, condition),
+            &[Type::INT8, Type::TEXT, Type::TEXT, Type::TEXT],
+            |row| {
+                let state_group = row.get(0);
+
+                // Every row is of a group in the range, as the same condition
+                // was used
+                if let Some((prev_state_group, has_rows)) = groups.get_mut(&state_group) {
+                    *has_rows = true;
+
+                    f(StateRow {
+                        state_group,
+                        prev_state_group: *prev_state_group,
+                        entry: Some((row.get(1), row.get(2), row.get(3))),
+                    });
+                }
+            },
+        );
+
+        // The groups with an empty delta still need to be passed on
+        for (state_group, (prev_state_group, has_rows)) in groups {
+            if !has_rows {
+                f(StateRow {
+                    state_group,
+                    prev_state_group,
+                    entry: None,
+                });
+            }
+        }
+    }
+
+    fn fetch_groups(&mut self, state_groups: &[i64], f: &mut dyn FnMut(StateRow<'_>)) {
+        self.client.fetch_groups(state_groups, f)
+    }
+
+    fn apply_changes(
+        &mut self,
+        room_id: &str,
+        old_map: &BTreeMap<i64, StateGroupEntry>,
+        new_map: &BTreeMap<i64, StateGroupEntry>,
+        plan_id: Option<&str>,
+    ) {
+        self.client
+            .apply_changes(room_id, old_map, new_map, plan_id)
+    }
+
+    fn read_commit_progress(&mut self, room_id: &str) -> Option<(String, Option<i64>)> {
+        self.client.read_commit_progress(room_id)
+    }
+
+    fn fetch_commit_plan(&mut self, room_id: &str, f: &mut dyn FnMut(StateRow<'_>)) {
+        self.client.fetch_commit_plan(room_id, f)
+    }
+}
+
+/// Query to get every group of a room along with its predecessor (if it has
+/// one), once the condition from `range_condition` is added
+const COPY_GROUPS_SQL: &str = r#"
+    SELECT m.id, e.prev_state_group
+    FROM state_groups AS m
+    LEFT JOIN state_group_edges AS e ON (m.id = e.state_group)
+"#;
+
+/// Query to get every row of the deltas of a room's groups, once the condition
+/// from `range_condition` is added
+const COPY_DELTAS_SQL: &str = r#"
+    SELECT s.state_group, s.type, s.state_key, s.event_id
+    FROM state_groups_state AS s
+    JOIN state_groups AS m ON (m.id = s.state_group)
+"#;
+
+/// The condition picking out the groups `fetch_groups_in_range` loads. `COPY`
+/// doesn't take parameters, so the values are written into the SQL
+///
+/// # Arguments
+///
+/// * `room_id`         -   The ID of the room in the database
+/// * `min_state_group` -   If specified, then only groups greater than (but
+///                         not equal) to this number are picked out
+/// * `max_state_group` -   Only groups lower than or equal to this number are
+///                         picked out
+fn range_condition(room_id: &str, min_state_group: Option<i64>, max_state_group: i64) -> String {
+    let mut condition = format!(
+        "m.room_id = {} AND m.id <= {}",
+        PGEscape(room_id),
+        max_state_group
+    );
+
+    if let Some(min) = min_state_group {
+        condition = format!("{} AND m.id > {}", condition, min);
+    }
+
+    condition
+}
+
+/// Runs the query given through `COPY ... TO STDOUT (FORMAT binary)`, passing
+/// each row to `f`
+///
+/// # Arguments
+///
+/// * `client`  -   A Postgres client to make requests with
+/// * `query`   -   The query whose rows are copied out
+/// * `types`   -   The types of the columns of the query
+/// * `f`       -   Called with each row in turn
+fn copy_rows<F>(client: &mut Client, query: &str, types: &[Type], mut f: F)
+where
+    F: FnMut(&BinaryCopyOutRow),
+{
+    let reader = client
+        .copy_out(format!("COPY ({}) TO STDOUT (FORMAT binary)", query).as_str())
+        .expect("Something went wrong while querying the database");
+
+    let mut rows = BinaryCopyOutIter::new(reader, types);
+
+    while let Some(row) = rows
+        .next()
+        .expect("Something went wrong while querying the database")
+    {
+        f(&row);
+    }
+}
+
+#[cfg(test)]
+mod copy_loader_tests {
+    use super::range_condition;
+
+    #[test]
+    fn range_condition_only_has_min_if_given() {
+        assert_eq!(
+            range_condition("room1", None, 13),
+            "m.room_id = $$room1$$ AND m.id <= 13"
+        );
+        assert_eq!(
+            range_condition("room1", Some(4), 13),
+            "m.room_id = $$room1$$ AND m.id <= 13 AND m.id > 4"
+        );
+    }
+}
@@ -0,0 +1,328 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Estimating how much compressing a room would save without loading all of it.
+//!
+//! The state groups of the room are split into chunks of consecutive groups (in
+//! the same way as the auto_compressor works through a room) and only a sample
+//! of the chunks is loaded. Each of these is compressed on its own, starting
+//! from empty levels, and the rows saved are scaled up to the whole room. The
+//! chunks are treated as a simple random sample, which is only approximately
+//! true when they are strided, and the confidence interval uses the normal
+//! approximation, so it is only a rough guide when few chunks are sampled.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use log::info;
+use postgres::Client;
+use rand::{seq::index, thread_rng};
+
+use crate::{database, CompressionStrategy, StateGroupEntry};
+
+/// How the chunks to load are chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleMethod {
+    /// Chunks spread evenly through the room, starting with the first
+    Strided,
+    /// Chunks chosen at random (without repeats)
+    Random,
+}
+
+impl FromStr for SampleMethod {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strided" => Ok(SampleMethod::Strided),
+            "random" => Ok(SampleMethod::Random),
+            _ => Err("Unknown sample method"),
+        }
+    }
+}
+
+/// Which parts of a room to load when estimating the savings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SamplePlan {
+    /// The number of chunks to load (if the room has fewer then all of them are
+    /// loaded)
+    pub chunks: usize,
+    /// The number of state groups in each chunk
+    pub chunk_size: i64,
+    /// How the chunks are chosen
+    pub method: SampleMethod,
+}
+
+/// Picks `samples` of the chunks numbered `0..chunk_count`, in ascending order
+pub fn choose_chunks(chunk_count: usize, samples: usize, method: SampleMethod) -> Vec<usize> {
+    if samples >= chunk_count {
+        return (0..chunk_count).collect();
+    }
+
+    match method {
+        // As samples < chunk_count these are all different
+        SampleMethod::Strided => (0..samples).map(|i| i * chunk_count / samples).collect(),
+        SampleMethod::Random => {
+            let mut chunks = index::sample(&mut thread_rng(), chunk_count, samples).into_vec();
+            chunks.sort_unstable();
+            chunks
+        }
+    }
+}
+
+/// How the compressor got on with a single sampled chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkSample {
+    /// The number of state groups in range
+    pub groups: usize,
+    /// The rows the groups in range took up before compressing
+    pub rows_before: usize,
+    /// The rows the groups in range took up after compressing
+    pub rows_after: usize,
+}
+
+/// An estimate of how much compressing a whole room would save
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+    /// The number of chunks the room splits into
+    pub total_chunks: usize,
+    /// The number of chunks that were loaded and compressed
+    pub sampled_chunks: usize,
+    /// The number of state groups in the sampled chunks
+    pub sampled_groups: usize,
+    /// The number of rows the sampled chunks took up
+    pub sampled_rows: usize,
+    /// The number of rows compressing the sampled chunks saved
+    pub sampled_rows_saved: i64,
+    /// The estimated number of rows the whole room takes up
+    pub estimated_rows: f64,
+    /// The estimated number of rows compressing the whole room would save
+    pub estimated_rows_saved: f64,
+    /// A 95% confidence interval for `estimated_rows_saved`. There isn't one
+    /// if only one chunk was sampled out of several
+    pub rows_saved_interval: Option<(f64, f64)>,
+}
+
+impl Estimate {
+    /// Scales the results for the sampled chunks up to all `total_chunks`
+    pub fn from_samples(total_chunks: usize, samples: &[ChunkSample]) -> Estimate {
+        let n = samples.len();
+        let saved: Vec<f64> = samples
+            .iter()
+            .map(|s| s.rows_before as f64 - s.rows_after as f64)
+            .collect();
+
+        let sampled_rows: usize = samples.iter().map(|s| s.rows_before).sum();
+        let sampled_rows_saved: f64 = saved.iter().sum();
+
+        let (estimated_rows, estimated_rows_saved) = if n == 0 {
+            (0.0, 0.0)
+        } else {
+            let scale = total_chunks as f64 / n as f64;
+            (sampled_rows as f64 * scale, sampled_rows_saved * scale)
+        };
+
+        let rows_saved_interval = if n >= total_chunks {
+            // Every chunk was compressed so there is nothing to estimate
+            Some((estimated_rows_saved, estimated_rows_saved))
+        } else if n < 2 {
+            None
+        } else {
+            let mean = sampled_rows_saved / n as f64;
+            let variance =
+                saved.iter().map(|s| (s - mean) * (s - mean)).sum::<f64>() / (n - 1) as f64;
+
+            // The variance of the total, including the finite population
+            // correction since the chunks are sampled without repeats
+            let total = total_chunks as f64;
+            let total_variance = total * total * variance / n as f64 * (1.0 - n as f64 / total);
+            let half_width = 1.96 * total_variance.sqrt();
+
+            Some((
+                estimated_rows_saved - half_width,
+                estimated_rows_saved + half_width,
+            ))
+        };
+
+        Estimate {
+            total_chunks,
+            sampled_chunks: n,
+            sampled_groups: samples.iter().map(|s| s.groups).sum(),
+            sampled_rows,
+            sampled_rows_saved: sampled_rows_saved as i64,
+            estimated_rows,
+            estimated_rows_saved,
+            rows_saved_interval,
+        }
+    }
+
+    /// The estimated fraction of the room's rows that compressing would save
+    pub fn saving_fraction(&self) -> f64 {
+        if self.estimated_rows == 0.0 {
+            0.0
+        } else {
+            self.estimated_rows_saved / self.estimated_rows
+        }
+    }
+}
+
+/// Compresses a single chunk and measures the groups in range
+fn sample_chunk(
+    state_group_map: &BTreeMap<i64, StateGroupEntry>,
+    strategy: &mut dyn CompressionStrategy,
+) -> ChunkSample {
+    let (new_state_group_map, _) = strategy.compress(state_group_map);
+
+    let rows_in_range = |map: &BTreeMap<i64, StateGroupEntry>| -> usize {
+        map.values()
+            .filter(|entry| entry.in_range)
+            .map(|entry| entry.state_map.len())
+            .sum()
+    };
+
+    ChunkSample {
+        groups: state_group_map.values().filter(|e| e.in_range).count(),
+        rows_before: rows_in_range(state_group_map),
+        rows_after: rows_in_range(&new_state_group_map),
+    }
+}
+
+/// Estimates how much compressing a room would save by compressing a sample
+/// of its chunks (see the module documentation)
+///
+/// Returns None if the room has no state groups
+///
+/// # Arguments
+///
+/// * `client`          -   A Postgres client to make requests with
+/// * `room_id`         -   The ID of the room in the database
+/// * `max_state_group` -   If specified, then only state groups lower than or
+///                         equal to this number are considered
+/// * `plan`            -   Which chunks to load
+/// * `build`           -   Creates the strategy to compress each chunk with
+///                         (a new one is used for each chunk)
+pub fn estimate_savings<B>(
+    client: &mut Client,
+    room_id: &str,
+    max_state_group: Option<i64>,
+    plan: &SamplePlan,
+    mut build: B,
+) -> Option<Estimate>
+where
+    B: FnMut() -> Box<dyn CompressionStrategy>,
+{
+    let mut samples = Vec::new();
+
+    let total_chunks = database::for_each_sampled_chunk(
+        client,
+        room_id,
+        max_state_group,
+        plan.chunk_size,
+        |chunk_count| choose_chunks(chunk_count, plan.chunks, plan.method),
+        |state_group_map| {
+            let sample = sample_chunk(&state_group_map, build().as_mut());
+            info!(
+                "Sampled {} state groups: {} rows, {} after compressing",
+                sample.groups, sample.rows_before, sample.rows_after
+            );
+            samples.push(sample);
+        },
+    );
+
+    if total_chunks == 0 {
+        return None;
+    }
+
+    Some(Estimate::from_samples(total_chunks, &samples))
+}
+
+#[cfg(test)]
+mod estimate_tests {
+    use super::{choose_chunks, ChunkSample, Estimate, SampleMethod};
+    use std::str::FromStr;
+
+    fn sample(rows_before: usize, rows_after: usize) -> ChunkSample {
+        ChunkSample {
+            groups: 10,
+            rows_before,
+            rows_after,
+        }
+    }
+
+    #[test]
+    fn sample_method_from_str_parses_methods() {
+        assert_eq!(SampleMethod::from_str("strided"), Ok(SampleMethod::Strided));
+        assert_eq!(SampleMethod::from_str("random"), Ok(SampleMethod::Random));
+        assert!(SampleMethod::from_str("sheep").is_err());
+    }
+
+    #[test]
+    fn choose_chunks_takes_all_chunks_if_there_are_few() {
+        assert_eq!(choose_chunks(3, 5, SampleMethod::Strided), vec![0, 1, 2]);
+        assert_eq!(choose_chunks(3, 3, SampleMethod::Random), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn choose_chunks_strided_spreads_chunks_out() {
+        assert_eq!(
+            choose_chunks(100, 4, SampleMethod::Strided),
+            vec![0, 25, 50, 75]
+        );
+        assert_eq!(choose_chunks(7, 3, SampleMethod::Strided), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn choose_chunks_random_picks_different_chunks() {
+        let chunks = choose_chunks(100, 10, SampleMethod::Random);
+
+        assert_eq!(chunks.len(), 10);
+        assert!(chunks.windows(2).all(|w| w[0] < w[1]));
+        assert!(chunks.iter().all(|c| *c < 100));
+    }
+
+    #[test]
+    fn estimate_scales_up_the_sample() {
+        let estimate = Estimate::from_samples(10, &[sample(100, 60), sample(100, 40)]);
+
+        assert_eq!(estimate.sampled_chunks, 2);
+        assert_eq!(estimate.sampled_groups, 20);
+        assert_eq!(estimate.sampled_rows, 200);
+        assert_eq!(estimate.sampled_rows_saved, 100);
+        assert_eq!(estimate.estimated_rows, 1000.0);
+        assert_eq!(estimate.estimated_rows_saved, 500.0);
+        assert_eq!(estimate.saving_fraction(), 0.5);
+
+        // The chunks saved 40 and 60 rows, so the variance is 200 and the
+        // variance of the total is 10 * 10 * 200 / 2 * (1 - 2 / 10) = 8000
+        let (low, high) = estimate.rows_saved_interval.unwrap();
+        let half_width = 1.96 * 8000f64.sqrt();
+        assert!((low - (500.0 - half_width)).abs() < 1e-6);
+        assert!((high - (500.0 + half_width)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn estimate_is_exact_if_every_chunk_was_sampled() {
+        let estimate = Estimate::from_samples(2, &[sample(100, 60), sample(100, 40)]);
+
+        assert_eq!(estimate.estimated_rows_saved, 100.0);
+        assert_eq!(estimate.rows_saved_interval, Some((100.0, 100.0)));
+    }
+
+    #[test]
+    fn estimate_has_no_interval_from_one_chunk() {
+        let estimate = Estimate::from_samples(10, &[sample(100, 60)]);
+
+        assert_eq!(estimate.estimated_rows_saved, 400.0);
+        assert_eq!(estimate.rows_saved_interval, None);
+    }
+}
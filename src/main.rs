@@ -42,5 +42,5 @@ fn main() {
         env_logger::Builder::from_env("RUST_LOG").init();
     }
 
-    comp_state::run(comp_state::Config::parse_arguments());
+    comp_state::run_rooms(comp_state::MultiRoomConfig::parse_arguments());
 }
@@ -0,0 +1,368 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compressing several rooms at once.
+//!
+//! Each room is run through `run` with its own copy of the config, on a pool
+//! of worker threads. A room that fails (e.g. because it has no state groups in
+//! the range given) doesn't stop the others, and a summary of how every room
+//! got on is logged at the end.
+
+use log::{info, warn};
+use rayon::{prelude::*, ThreadPoolBuilder};
+use std::{
+    any::Any,
+    fs::File,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
+
+use crate::{database, run_with_client, Config, ConnectionPool, RunSummary};
+#[cfg(feature = "sqlite")]
+use crate::{run_with_store, sqlite};
+
+/// Which rooms to compress
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomSelection {
+    /// The rooms with these IDs
+    Listed(Vec<String>),
+    /// Every room with more than this many rows in `state_groups_state`
+    AboveRows(i64),
+}
+
+/// Contains configuration information for compressing several rooms
+pub struct MultiRoomConfig {
+    // The options used for every room (its room_id and output_file are ignored)
+    config: Config,
+    // The rooms to compress
+    rooms: RoomSelection,
+    // The file to write the SQL to. If there is more than one room then each
+    // room gets its own file, with the room ID added to the name
+    output_path: Option<String>,
+    // How many rooms to compress at once
+    workers: usize,
+}
+
+impl MultiRoomConfig {
+    /// Creates the config for compressing several rooms
+    ///
+    /// # Arguments
+    ///
+    /// * `config`      -   The options used for every room. Its room_id and
+    ///                     output_file are ignored
+    /// * `rooms`       -   The rooms to compress
+    /// * `output_path` -   The file to write the SQL to (see `output_path_for_room`)
+    /// * `workers`     -   How many rooms to compress at once
+    pub fn new(
+        config: Config,
+        rooms: RoomSelection,
+        output_path: Option<String>,
+        workers: usize,
+    ) -> MultiRoomConfig {
+        MultiRoomConfig {
+            config,
+            rooms,
+            output_path,
+            workers,
+        }
+    }
+
+    /// Build up config from command line arguments
+    pub fn parse_arguments() -> MultiRoomConfig {
+        let matches = Config::app().get_matches();
+
+        let config = Config::from_matches(&matches);
+
+        let rooms = match matches.value_of("rooms_above") {
+            Some(rows) => RoomSelection::AboveRows(rows.parse().expect("ROWS must be an integer")),
+            None => RoomSelection::Listed(
+                matches
+                    .values_of("room_id")
+                    .expect("room_id should be required unless rooms_above is given")
+                    .map(String::from)
+                    .collect(),
+            ),
+        };
+
+        let output_path = matches.value_of("output_file").map(String::from);
+
+        let workers = matches
+            .value_of("workers")
+            .and_then(|w| w.parse().ok())
+            .filter(|w| *w > 0)
+            .expect("workers must be a positive integer");
+
+        MultiRoomConfig {
+            config,
+            rooms,
+            output_path,
+            workers,
+        }
+    }
+
+    /// Converts this into the config for a single room
+    ///
+    /// Panics unless exactly one room was listed
+    pub fn into_single_room(self) -> Config {
+        match &self.rooms {
+            RoomSelection::Listed(rooms) if rooms.len() == 1 => {
+                self.config_for_room(&rooms[0], false)
+            }
+            _ => panic!("Exactly one room must be given"),
+        }
+    }
+
+    /// Builds the config for compressing one of the rooms, creating its
+    /// output file
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id`     -   The room to compress
+    /// * `own_file`    -   Whether to add the room ID to the output file name
+    fn config_for_room(&self, room_id: &str, own_file: bool) -> Config {
+        let output_file = self.output_path.as_ref().map(|path| {
+            let path = if own_file {
+                output_path_for_room(path, room_id)
+            } else {
+                PathBuf::from(path)
+            };
+            File::create(path).unwrap_or_else(|e| panic!("Unable to create output file: {}", e))
+        });
+
+        self.config.for_room(room_id.to_string(), output_file)
+    }
+}
+
+/// How the compressor got on with a single room
+#[derive(Debug)]
+pub struct RoomSummary {
+    // The ID of the room
+    pub room_id: String,
+    // What the compressor did, or the reason it failed
+    pub result: Result<RunSummary, String>,
+}
+
+/// Compresses each of the rooms in the config, using up to `workers` threads
+///
+/// Returns how each room got on, in the order the rooms were given in (or
+/// biggest first when using `RoomSelection::AboveRows`)
+///
+/// # Arguments
+///
+/// * `config`  -   A MultiRoomConfig struct that controlls the run
+pub fn run_rooms(config: MultiRoomConfig) -> Vec<RoomSummary> {
+    // Each worker takes a connection for the room it is on and gives it back
+    // afterwards, so connections are made for each worker rather than each room
+    let connections = ConnectionPool::new(&config.config.db_url)
+        .with_tls(&config.config.tls)
+        .with_max_idle(config.workers);
+
+    let room_ids = match &config.rooms {
+        RoomSelection::Listed(room_ids) => room_ids.clone(),
+        RoomSelection::AboveRows(rows) => {
+            info!("Finding rooms with more than {} rows of state...", rows);
+            rooms_with_more_rows_than(&config.config.db_url, &connections, *rows)
+        }
+    };
+
+    info!(
+        "Compressing {} rooms using {} workers",
+        room_ids.len(),
+        config.workers
+    );
+
+    let own_files = room_ids.len() > 1;
+
+    // The graphs are always written to the same files
+    if own_files && config.config.graphs {
+        panic!("Graphs can only be output when processing a single room");
+    }
+
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(config.workers)
+        .build()
+        .expect("Unable to start the worker threads");
+
+    let summaries: Vec<RoomSummary> = pool.install(|| {
+        room_ids
+            .par_iter()
+            .map(|room_id| {
+                // Stop a panic in one room from taking down the others
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    run_room(config.config_for_room(room_id, own_files), &connections)
+                }))
+                .map_err(|e| panic_message(e.as_ref()));
+
+                RoomSummary {
+                    room_id: room_id.clone(),
+                    result,
+                }
+            })
+            .collect()
+    });
+
+    log_summaries(&summaries);
+
+    summaries
+}
+
+/// Returns the rooms with more than `min_rows` rows of state, from the SQLite
+/// database if the URL is for one or else over a connection from the pool
+#[cfg_attr(not(feature = "sqlite"), allow(unused_variables))]
+fn rooms_with_more_rows_than(
+    db_url: &str,
+    connections: &ConnectionPool,
+    min_rows: i64,
+) -> Vec<String> {
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = sqlite::path_from_url(db_url) {
+        return sqlite::get_rooms_with_more_rows_than(&sqlite::connect(path), min_rows);
+    }
+
+    database::get_rooms_with_more_rows_than(&mut connections.get(), min_rows)
+}
+
+/// Compresses a single room over a connection from the pool, or (as that is
+/// cheap) by opening the SQLite database if the URL is for one
+fn run_room(config: Config, connections: &ConnectionPool) -> RunSummary {
+    #[cfg(feature = "sqlite")]
+    if let Some(path) = sqlite::path_from_url(&config.db_url) {
+        let mut connection = sqlite::connect(path);
+
+        return run_with_store(config, &mut connection);
+    }
+
+    run_with_client(config, &mut connections.get())
+}
+
+/// Adds the room ID to the name of the output file, before the extension (if
+/// there is one)
+fn output_path_for_room(path: &str, room_id: &str) -> PathBuf {
+    let path = Path::new(path);
+
+    let mut file_name = path.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(room_id);
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+
+    path.with_file_name(file_name)
+}
+
+/// Gets the message a thread panicked with
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Unknown error".to_string()
+    }
+}
+
+/// Logs how each room got on, followed by the totals
+fn log_summaries(summaries: &[RoomSummary]) {
+    let mut failed = 0;
+    let mut original_num_rows = 0;
+    let mut new_num_rows = 0;
+
+    info!("Summary:");
+
+    for summary in summaries {
+        match &summary.result {
+            Ok(run_summary) => {
+                let ratio =
+                    (run_summary.new_num_rows as f64) / (run_summary.original_num_rows as f64);
+                info!(
+                    "  {}: {} -> {} rows ({:.2}%), {} state groups changed{}",
+                    summary.room_id,
+                    run_summary.original_num_rows,
+                    run_summary.new_num_rows,
+                    ratio * 100.,
+                    run_summary.state_groups_changed,
+                    if run_summary.changes_written {
+                        ""
+                    } else {
+                        " (not written)"
+                    }
+                );
+
+                if let (Some(before), Some(after)) = (
+                    &run_summary.query_cost_before,
+                    &run_summary.query_cost_after,
+                ) {
+                    info!(
+                        "    state query iterations: mean {:.1} -> {:.1}, worst case {} -> {}",
+                        before.mean, after.mean, before.max, after.max
+                    );
+                }
+
+                if run_summary.changes_written {
+                    original_num_rows += run_summary.original_num_rows;
+                    new_num_rows += run_summary.new_num_rows;
+                }
+            }
+            Err(e) => {
+                failed += 1;
+                warn!("  {}: failed: {}", summary.room_id, e);
+            }
+        }
+    }
+
+    info!(
+        "{} rooms compressed ({} failed), {} rows reduced to {}",
+        summaries.len() - failed,
+        failed,
+        original_num_rows,
+        new_num_rows
+    );
+}
+
+#[cfg(test)]
+mod rooms_tests {
+    use std::path::PathBuf;
+
+    use super::{output_path_for_room, panic_message};
+
+    #[test]
+    fn output_path_for_room_adds_room_before_extension() {
+        assert_eq!(
+            output_path_for_room("out.sql", "!room:example.com"),
+            PathBuf::from("out.!room:example.com.sql")
+        );
+        assert_eq!(
+            output_path_for_room("/tmp/out.sql", "!room:example.com"),
+            PathBuf::from("/tmp/out.!room:example.com.sql")
+        );
+    }
+
+    #[test]
+    fn output_path_for_room_works_without_extension() {
+        assert_eq!(
+            output_path_for_room("out", "!room:example.com"),
+            PathBuf::from("out.!room:example.com")
+        );
+    }
+
+    #[test]
+    fn panic_message_gets_message() {
+        let payload = std::panic::catch_unwind(|| panic!("No state groups found")).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "No state groups found");
+
+        let payload = std::panic::catch_unwind(|| panic!("Missing {}", 3)).unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "Missing 3");
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serialisation of the compressor's types (with the `serde` feature).
+//!
+//! This allows the state of the levels, the state groups and the statistics of
+//! a run to be saved or sent somewhere other than the `state_compressor_state`
+//! table. `Stats`, `ChunkStats` and `QueryCost` derive their implementations,
+//! so their fields appear under their own names. `Level` and `StateGroupEntry`
+//! are written out through the `*Repr` structs below, so that the format
+//! doesn't change if their internals do:
+//!
+//! ```json
+//! {"max_length": 3, "current_length": 2, "head": 13}
+//! {"in_range": true, "prev_state_group": 12, "state_map": [["m.room.name", "", "$event"]]}
+//! ```
+//!
+//! The state map is a list of `[type, state_key, event_id]` rows sorted by type
+//! and then state key, so the same entry is always written out the same way.
+//! The binary format is CBOR, which has the same structure.
+
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use state_map::StateMap;
+
+use crate::{compressor::Level, StateGroupEntry};
+
+/// The serialised form of a `Level`
+#[derive(Serialize, Deserialize)]
+struct LevelRepr {
+    max_length: usize,
+    current_length: usize,
+    head: Option<i64>,
+}
+
+impl LevelRepr {
+    /// Checks the same things about the level as `read_room_compressor_state`
+    /// does when loading it from the database
+    fn into_level(self) -> Result<Level, String> {
+        if self.head.is_none() && self.current_length != 0 {
+            return Err(format!(
+                "Level has no head but current length is {}",
+                self.current_length
+            ));
+        }
+
+        if self.current_length > self.max_length {
+            return Err(format!(
+                "Level has length {} but max size {}",
+                self.current_length, self.max_length
+            ));
+        }
+
+        Ok(Level::restore(
+            self.max_length,
+            self.current_length,
+            self.head,
+        ))
+    }
+}
+
+impl Serialize for Level {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        LevelRepr {
+            max_length: self.get_max_length(),
+            current_length: self.get_current_length(),
+            head: self.get_head(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Level {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Level, D::Error> {
+        LevelRepr::deserialize(deserializer)?
+            .into_level()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// The serialised form of a `StateGroupEntry`
+#[derive(Serialize, Deserialize)]
+struct StateGroupEntryRepr {
+    in_range: bool,
+    prev_state_group: Option<i64>,
+    state_map: Vec<(String, String, String)>,
+}
+
+impl Serialize for StateGroupEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state_map: Vec<(String, String, String)> = self
+            .state_map
+            .iter()
+            .map(|((t, s), e)| (t.to_string(), s.to_string(), e.to_string()))
+            .collect();
+        state_map.sort();
+
+        StateGroupEntryRepr {
+            in_range: self.in_range,
+            prev_state_group: self.prev_state_group,
+            state_map,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for StateGroupEntry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<StateGroupEntry, D::Error> {
+        let repr = StateGroupEntryRepr::deserialize(deserializer)?;
+
+        let mut state_map = StateMap::new();
+        for (t, s, e) in &repr.state_map {
+            if state_map.contains_key(t, s) {
+                return Err(serde::de::Error::custom(format!(
+                    "Duplicate state key ({}, {})",
+                    t, s
+                )));
+            }
+            state_map.insert(t, s, e.as_str().into());
+        }
+
+        Ok(StateGroupEntry {
+            in_range: repr.in_range,
+            prev_state_group: repr.prev_state_group,
+            state_map,
+        })
+    }
+}
+
+/// Serialises a value (e.g. a `Vec<Level>` or a `ChunkStats`) as JSON
+pub fn to_json<T: Serialize>(value: &T) -> Result<String, String> {
+    serde_json::to_string(value).map_err(|e| format!("Unable to serialise as JSON: {}", e))
+}
+
+/// Deserialises a value from JSON written by `to_json`
+pub fn from_json<T: DeserializeOwned>(json: &str) -> Result<T, String> {
+    serde_json::from_str(json).map_err(|e| format!("Unable to deserialise JSON: {}", e))
+}
+
+/// Serialises a value as CBOR
+pub fn to_cbor<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    let mut cbor = Vec::new();
+    ciborium::ser::into_writer(value, &mut cbor)
+        .map_err(|e| format!("Unable to serialise as CBOR: {}", e))?;
+
+    Ok(cbor)
+}
+
+/// Deserialises a value from CBOR written by `to_cbor`
+pub fn from_cbor<T: DeserializeOwned>(cbor: &[u8]) -> Result<T, String> {
+    ciborium::de::from_reader(cbor).map_err(|e| format!("Unable to deserialise CBOR: {}", e))
+}
+
+#[cfg(test)]
+mod serialization_tests {
+    use std::collections::BTreeMap;
+
+    use state_map::StateMap;
+
+    use super::{from_cbor, from_json, to_cbor, to_json, LevelRepr};
+    use crate::{compressor::Level, ChunkStats, QueryCost, StateGroupEntry, Stats};
+
+    fn entry() -> StateGroupEntry {
+        let mut state_map = StateMap::new();
+        state_map.insert("m.room.name", "", "$name".into());
+        state_map.insert("m.room.member", "@bob:example.com", "$bob".into());
+        state_map.insert("m.room.member", "@alice:example.com", "$alice".into());
+
+        StateGroupEntry {
+            in_range: true,
+            prev_state_group: Some(12),
+            state_map,
+        }
+    }
+
+    #[test]
+    fn level_json_is_stable() {
+        let level = Level::restore(3, 2, Some(13));
+
+        let json = to_json(&level).unwrap();
+        assert_eq!(json, r#"{"max_length":3,"current_length":2,"head":13}"#);
+        assert_eq!(from_json::<Level>(&json).unwrap(), level);
+    }
+
+    #[test]
+    fn level_json_is_validated() {
+        // Too long for the level
+        assert!(from_json::<Level>(r#"{"max_length":3,"current_length":4,"head":13}"#).is_err());
+        // Not empty but has no head
+        assert!(from_json::<Level>(r#"{"max_length":3,"current_length":2,"head":null}"#).is_err());
+        // An empty level without a head is fine
+        assert_eq!(
+            from_json::<Level>(r#"{"max_length":3,"current_length":0,"head":null}"#).unwrap(),
+            Level::new(3)
+        );
+    }
+
+    #[test]
+    fn level_cbor_round_trips_and_is_validated() {
+        let levels = vec![Level::restore(3, 2, Some(13)), Level::new(5)];
+
+        let cbor = to_cbor(&levels).unwrap();
+        assert_eq!(from_cbor::<Vec<Level>>(&cbor).unwrap(), levels);
+
+        let bad = to_cbor(&vec![LevelRepr {
+            max_length: 3,
+            current_length: 4,
+            head: Some(13),
+        }])
+        .unwrap();
+        assert!(from_cbor::<Vec<Level>>(&bad).is_err());
+    }
+
+    #[test]
+    fn level_cbor_is_stable() {
+        // A map of the three fields, with the smallest encoding of each integer
+        let mut expected = vec![0xa3];
+        expected.push(0x6a);
+        expected.extend_from_slice(b"max_length");
+        expected.push(0x03);
+        expected.push(0x6e);
+        expected.extend_from_slice(b"current_length");
+        expected.push(0x02);
+        expected.push(0x64);
+        expected.extend_from_slice(b"head");
+        expected.push(0x0d);
+
+        assert_eq!(to_cbor(&Level::restore(3, 2, Some(13))).unwrap(), expected);
+    }
+
+    #[test]
+    fn state_group_entry_json_is_stable() {
+        let json = to_json(&entry()).unwrap();
+
+        // The rows are sorted, whatever order they were inserted in
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"in_range":true,"prev_state_group":12,"state_map":["#,
+                r#"["m.room.member","@alice:example.com","$alice"],"#,
+                r#"["m.room.member","@bob:example.com","$bob"],"#,
+                r#"["m.room.name","","$name"]]}"#
+            )
+        );
+        assert_eq!(from_json::<StateGroupEntry>(&json).unwrap(), entry());
+    }
+
+    #[test]
+    fn state_group_entry_rejects_duplicate_keys() {
+        let json = concat!(
+            r#"{"in_range":true,"prev_state_group":null,"state_map":["#,
+            r#"["m.room.name","","$one"],["m.room.name","","$two"]]}"#
+        );
+
+        assert!(from_json::<StateGroupEntry>(json).is_err());
+    }
+
+    #[test]
+    fn state_group_map_cbor_round_trips() {
+        let mut map = BTreeMap::new();
+        map.insert(12, StateGroupEntry::default());
+        map.insert(13, entry());
+
+        let cbor = to_cbor(&map).unwrap();
+        assert_eq!(
+            from_cbor::<BTreeMap<i64, StateGroupEntry>>(&cbor).unwrap(),
+            map
+        );
+    }
+
+    #[test]
+    fn stats_default_missing_fields() {
+        let stats: Stats = from_json(r#"{"state_groups_changed":4}"#).unwrap();
+
+        assert_eq!(stats.state_groups_changed, 4);
+        assert_eq!(stats.resets_no_suitable_prev, 0);
+    }
+
+    #[test]
+    fn chunk_stats_round_trip() {
+        let chunk_stats = ChunkStats {
+            new_level_info: vec![Level::restore(3, 1, Some(20))],
+            last_compressed_group: 20,
+            original_num_rows: 100,
+            new_num_rows: 40,
+            query_cost_before: QueryCost { mean: 5.5, max: 10 },
+            query_cost_after: QueryCost { mean: 2.0, max: 3 },
+            backfill_groups: 0,
+            snapshots: 1,
+            commited: true,
+        };
+
+        let json = to_json(&chunk_stats).unwrap();
+        let from_json: ChunkStats = from_json(&json).unwrap();
+        assert_eq!(from_json.new_level_info, chunk_stats.new_level_info);
+        assert_eq!(from_json.query_cost_before, chunk_stats.query_cost_before);
+        assert_eq!(from_json.new_num_rows, 40);
+
+        let cbor = to_cbor(&chunk_stats).unwrap();
+        let from_cbor: ChunkStats = from_cbor(&cbor).unwrap();
+        assert_eq!(from_cbor.new_level_info, chunk_stats.new_level_info);
+        assert!(from_cbor.commited);
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A cache of the full (collapsed) state of recently used state groups.
+//!
+//! Collapsing a state group from scratch means walking its whole chain of
+//! predecessors and applying every delta along the way. Both the compressor and
+//! the verification step work through the state groups in order, and most of
+//! the groups they look at are close descendants of groups they have just
+//! looked at (the previous group, or the head of one of the levels). So instead
+//! the walk up the chain stops at the first group that is in the cache, and only
+//! the deltas below that are applied.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{StateGroupEntry, StateGroupMap};
+
+/// How many collapsed states a cache holds unless told otherwise. This is
+/// plenty for the access patterns of the compressor, as the groups that are
+/// looked at repeatedly are the heads of the levels and the most recent groups.
+pub const DEFAULT_CACHE_SIZE: usize = 64;
+
+/// Works out the full state of the groups in a map, remembering the most
+/// recently used results
+///
+/// The map mustn't be changed while the cache is in use, as the cached states
+/// would no longer be correct
+pub struct StateCache<'a, M: StateGroupMap = BTreeMap<i64, StateGroupEntry>> {
+    map: &'a M,
+    capacity: usize,
+    // The cached states, along with when they were last used
+    entries: HashMap<i64, (M::State, u64)>,
+    // The cached state groups, in order of when they were last used
+    recently_used: BTreeMap<u64, i64>,
+    // Incremented every time the cache is used
+    clock: u64,
+}
+
+impl<'a, M: StateGroupMap> StateCache<'a, M> {
+    /// Creates an empty cache for the state groups in `map` which holds up to
+    /// `DEFAULT_CACHE_SIZE` states
+    pub fn new(map: &'a M) -> StateCache<'a, M> {
+        StateCache::with_capacity(map, DEFAULT_CACHE_SIZE)
+    }
+
+    /// Creates an empty cache for the state groups in `map` which holds up to
+    /// `capacity` states
+    pub fn with_capacity(map: &'a M, capacity: usize) -> StateCache<'a, M> {
+        StateCache {
+            map,
+            capacity,
+            entries: HashMap::new(),
+            recently_used: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Gets the full state for a given group (this is the same as calling
+    /// `collapse_state_maps` on the map, just faster)
+    ///
+    /// Panics if the group, or any of its predecessors, are not in the map
+    pub fn get(&mut self, state_group: i64) -> M::State {
+        let mut state_map = M::State::default();
+
+        // Walk up the chain until we reach a group that is in the cache (or
+        // the start of the chain)
+        let mut stack = Vec::new();
+        let mut next = Some(state_group);
+
+        while let Some(sg) = next {
+            if let Some(cached) = self.touch(sg) {
+                state_map = cached.clone();
+                break;
+            }
+
+            if !self.map.has_group(sg) {
+                panic!("Missing {}", sg);
+            }
+
+            stack.push(sg);
+            next = self.map.group_prev(sg);
+        }
+
+        // Then apply the deltas of all the groups we didn't have cached
+        for sg in stack.iter().rev() {
+            M::apply_delta(&mut state_map, self.map.group_delta(*sg));
+        }
+
+        if !stack.is_empty() {
+            self.insert(state_group, state_map.clone());
+        }
+
+        state_map
+    }
+
+    /// Marks a group as just used, returning its state if it is cached
+    fn touch(&mut self, state_group: i64) -> Option<&M::State> {
+        let (state_map, last_used) = self.entries.get_mut(&state_group)?;
+
+        self.clock += 1;
+        self.recently_used.remove(last_used);
+        self.recently_used.insert(self.clock, state_group);
+        *last_used = self.clock;
+
+        Some(state_map)
+    }
+
+    /// Adds a state to the cache, throwing out the least recently used one if
+    /// the cache is full
+    fn insert(&mut self, state_group: i64, state_map: M::State) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            let oldest = self.recently_used.keys().next().copied();
+            if let Some(sg) = oldest.and_then(|tick| self.recently_used.remove(&tick)) {
+                self.entries.remove(&sg);
+            }
+        }
+
+        self.clock += 1;
+        self.recently_used.insert(self.clock, state_group);
+        self.entries.insert(state_group, (state_map, self.clock));
+    }
+}
+
+#[cfg(test)]
+mod state_cache_tests {
+    use std::collections::BTreeMap;
+
+    use state_map::StateMap;
+
+    use super::StateCache;
+    use crate::{collapse_state_maps, StateGroupEntry};
+
+    /// Builds the following structure
+    ///
+    /// 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+    ///
+    /// Each group i has state:
+    ///     ('node','is',      i)
+    ///     ('group',  j, 'seen') where j is less than or equal to i
+    fn line_with_state() -> BTreeMap<i64, StateGroupEntry> {
+        let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+        let mut prev = None;
+
+        for i in 0i64..=13i64 {
+            let mut entry = StateGroupEntry {
+                in_range: true,
+                prev_state_group: prev,
+                state_map: StateMap::new(),
+            };
+            entry
+                .state_map
+                .insert("group", &i.to_string(), "seen".into());
+            entry.state_map.insert("node", "is", i.to_string().into());
+
+            initial.insert(i, entry);
+
+            prev = Some(i)
+        }
+
+        initial
+    }
+
+    #[test]
+    fn get_matches_collapse_state_maps() {
+        let initial = line_with_state();
+        let mut cache = StateCache::new(&initial);
+
+        // Go backwards as well as forwards so that some groups have a cached
+        // ancestor and some don't
+        for sg in (0i64..=13i64).chain((0i64..=13i64).rev()) {
+            assert_eq!(cache.get(sg), collapse_state_maps(&initial, sg));
+        }
+    }
+
+    #[test]
+    fn get_matches_collapse_state_maps_when_full() {
+        let initial = line_with_state();
+        let mut cache = StateCache::with_capacity(&initial, 2);
+
+        for sg in [13, 3, 7, 12, 3, 0, 13].iter() {
+            assert_eq!(cache.get(*sg), collapse_state_maps(&initial, *sg));
+        }
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let initial = line_with_state();
+        let mut cache = StateCache::with_capacity(&initial, 2);
+
+        cache.get(3);
+        cache.get(7);
+        // 3 is now more recently used than 7 so 7 should be thrown out
+        cache.get(3);
+        cache.get(2);
+
+        assert!(cache.entries.contains_key(&3));
+        assert!(!cache.entries.contains_key(&7));
+        assert!(cache.entries.contains_key(&2));
+        assert_eq!(cache.recently_used.len(), 2);
+    }
+
+    #[test]
+    fn cache_with_no_capacity_still_works() {
+        let initial = line_with_state();
+        let mut cache = StateCache::with_capacity(&initial, 0);
+
+        assert_eq!(cache.get(5), collapse_state_maps(&initial, 5));
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Missing 1")]
+    fn get_panics_if_pred_not_in_map() {
+        let mut initial = line_with_state();
+        initial.remove(&1);
+
+        let mut cache = StateCache::new(&initial);
+        cache.get(3);
+    }
+}
@@ -0,0 +1,363 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Where the state groups are loaded from and the changes written to.
+//!
+//! The compressor only needs a few things from the database: the rows of the
+//! state groups in a range, the rows of some particular groups (predecessors
+//! outside the range, and the heads of the levels when continuing), a way to
+//! apply the changes, and the progress recorded by an interrupted commit. The
+//! `StateStore` trait covers these, so that the loading and checking can be
+//! used with something other than Synapse's Postgres database (such as an
+//! in-memory store in tests). `postgres::Client` implements it with the same
+//! queries as before.
+//!
+//! The options that only make sense for Postgres (streaming, sampling, finding
+//! rooms and forward extremities) still take a `Client`.
+
+use std::collections::BTreeMap;
+
+use crate::StateGroupEntry;
+
+/// A row of a state group, as read from a `StateStore`
+///
+/// A group with a delta of several entries is read as several rows with the
+/// same group and predecessor. A group with an empty delta is read as a single
+/// row without an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateRow<'a> {
+    // The state group the row belongs to
+    pub state_group: i64,
+    // The group's predecessor (if it has one)
+    pub prev_state_group: Option<i64>,
+    // The type, state key and event ID of one entry of the group's delta
+    pub entry: Option<(&'a str, &'a str, &'a str)>,
+}
+
+/// Somewhere the state groups of rooms are kept
+pub trait StateStore {
+    /// Returns the ID of the last of the first `groups_to_compress` state
+    /// groups of the room after `min_state_group` (or of the last group of the
+    /// room if `groups_to_compress` isn't given), or None if there are none
+    ///
+    /// `min_state_group` is only used if `groups_to_compress` is given, and
+    /// only groups up to `max_state_group` (if given) are considered
+    fn find_max_group(
+        &mut self,
+        room_id: &str,
+        min_state_group: Option<i64>,
+        groups_to_compress: Option<i64>,
+        max_state_group: Option<i64>,
+    ) -> Option<i64>;
+
+    /// Passes each row of the room's state groups after `min_state_group` (if
+    /// given) and up to `max_state_group` to `f`
+    fn fetch_groups_in_range(
+        &mut self,
+        room_id: &str,
+        min_state_group: Option<i64>,
+        max_state_group: i64,
+        f: &mut dyn FnMut(StateRow<'_>),
+    );
+
+    /// Passes each row of the given state groups to `f`. Groups that don't
+    /// exist are left out
+    fn fetch_groups(&mut self, state_groups: &[i64], f: &mut dyn FnMut(StateRow<'_>));
+
+    /// Changes each state group in `old_map` whose predecessor or delta is
+    /// different in `new_map` to match `new_map`, one group at a time in order
+    ///
+    /// If `plan_id` is given then the changes are saved as the plan with that
+    /// ID before any of them are made, and each change is recorded (along with
+    /// the change itself) as the progress of that plan, so that they can be
+    /// read by `read_commit_progress` and `fetch_commit_plan` if the changes
+    /// are interrupted. Both are cleared once every change has been made
+    fn apply_changes(
+        &mut self,
+        room_id: &str,
+        old_map: &BTreeMap<i64, StateGroupEntry>,
+        new_map: &BTreeMap<i64, StateGroupEntry>,
+        plan_id: Option<&str>,
+    );
+
+    /// Returns the plan ID of an interrupted `apply_changes` and the last
+    /// state group it changed (if it changed any), or None if there is nothing
+    /// to resume for the room
+    fn read_commit_progress(&mut self, room_id: &str) -> Option<(String, Option<i64>)>;
+
+    /// Passes each row of the plan saved by an interrupted `apply_changes` to
+    /// `f`, in the same form as the rows of the state groups. Nothing is passed
+    /// if there is nothing to resume for the room
+    fn fetch_commit_plan(&mut self, room_id: &str, f: &mut dyn FnMut(StateRow<'_>));
+}
+
+// The fixture and checks are shared with the tests of the other stores, so that
+// each `StateStore` implementation is run through the same tests
+#[cfg(test)]
+pub(crate) mod store_tests {
+    use std::collections::BTreeMap;
+
+    use state_map::StateMap;
+    use string_cache::DefaultAtom as Atom;
+
+    use super::{StateRow, StateStore};
+    use crate::{
+        collapse_state_maps, continue_run, database::get_data_from_db, CompressorOptions, Level,
+        StateGroupEntry, Strategy,
+    };
+
+    /// Keeps the state groups in memory, as the room, predecessor and delta
+    /// of each group
+    #[derive(Default)]
+    struct MemoryStore {
+        groups: BTreeMap<i64, (String, Option<i64>, StateMap<Atom>)>,
+    }
+
+    impl MemoryStore {
+        fn add_room(&mut self, room_id: &str, map: &BTreeMap<i64, StateGroupEntry>) {
+            for (sg, entry) in map {
+                self.groups.insert(
+                    *sg,
+                    (
+                        room_id.to_string(),
+                        entry.prev_state_group,
+                        entry.state_map.clone(),
+                    ),
+                );
+            }
+        }
+
+        fn row_count(&self) -> usize {
+            self.groups.values().map(|(_, _, delta)| delta.len()).sum()
+        }
+
+        fn pass_rows(&self, sg: i64, f: &mut dyn FnMut(StateRow<'_>)) {
+            let (_, prev, delta) = &self.groups[&sg];
+
+            let mut row = StateRow {
+                state_group: sg,
+                prev_state_group: *prev,
+                entry: None,
+            };
+
+            if delta.is_empty() {
+                f(row);
+            }
+
+            for ((t, s), e) in delta.iter() {
+                row.entry = Some((t, s, &**e));
+                f(row);
+            }
+        }
+    }
+
+    impl StateStore for MemoryStore {
+        fn find_max_group(
+            &mut self,
+            room_id: &str,
+            min_state_group: Option<i64>,
+            groups_to_compress: Option<i64>,
+            max_state_group: Option<i64>,
+        ) -> Option<i64> {
+            // As with Postgres, the minimum is only used with groups_to_compress
+            let min = groups_to_compress.and(min_state_group).unwrap_or(i64::MIN);
+            let max = max_state_group.unwrap_or(i64::MAX);
+
+            let groups = self
+                .groups
+                .iter()
+                .filter(|(_, (room, _, _))| room == room_id)
+                .map(|(sg, _)| *sg)
+                .filter(|sg| min < *sg && *sg <= max);
+
+            match groups_to_compress {
+                Some(count) => groups.take(count as usize).max(),
+                None => groups.max(),
+            }
+        }
+
+        fn fetch_groups_in_range(
+            &mut self,
+            room_id: &str,
+            min_state_group: Option<i64>,
+            max_state_group: i64,
+            f: &mut dyn FnMut(StateRow<'_>),
+        ) {
+            let min = min_state_group.unwrap_or(i64::MIN);
+
+            for (sg, (room, _, _)) in &self.groups {
+                if room == room_id && min < *sg && *sg <= max_state_group {
+                    self.pass_rows(*sg, f);
+                }
+            }
+        }
+
+        fn fetch_groups(&mut self, state_groups: &[i64], f: &mut dyn FnMut(StateRow<'_>)) {
+            for sg in state_groups {
+                if self.groups.contains_key(sg) {
+                    self.pass_rows(*sg, f);
+                }
+            }
+        }
+
+        fn apply_changes(
+            &mut self,
+            _room_id: &str,
+            old_map: &BTreeMap<i64, StateGroupEntry>,
+            new_map: &BTreeMap<i64, StateGroupEntry>,
+            _plan_id: Option<&str>,
+        ) {
+            for (sg, old_entry) in old_map {
+                let new_entry = &new_map[sg];
+
+                if old_entry.prev_state_group != new_entry.prev_state_group
+                    || old_entry.state_map != new_entry.state_map
+                {
+                    let group = self.groups.get_mut(sg).unwrap();
+                    group.1 = new_entry.prev_state_group;
+                    group.2 = new_entry.state_map.clone();
+                }
+            }
+        }
+
+        fn read_commit_progress(&mut self, _room_id: &str) -> Option<(String, Option<i64>)> {
+            None
+        }
+
+        fn fetch_commit_plan(&mut self, _room_id: &str, _f: &mut dyn FnMut(StateRow<'_>)) {}
+    }
+
+    /// Builds 0-1-2 3-4-5 6-7-8 9-10-11 12-13, where each group i has state:
+    ///     ('node','is',      i)
+    ///     ('group',  j, 'seen') - for all j less than i
+    pub(crate) fn line_segments_with_state() -> BTreeMap<i64, StateGroupEntry> {
+        let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+        let mut prev = None;
+
+        for i in 0i64..=13i64 {
+            let mut entry = StateGroupEntry {
+                in_range: true,
+                prev_state_group: prev,
+                state_map: StateMap::new(),
+            };
+
+            // The first group of each segment stores its full state
+            if i % 3 == 0 {
+                entry.prev_state_group = None;
+                for j in 0..i {
+                    entry
+                        .state_map
+                        .insert("group", &j.to_string(), "seen".into());
+                }
+            }
+            entry
+                .state_map
+                .insert("group", &i.to_string(), "seen".into());
+            entry.state_map.insert("node", "is", i.to_string().into());
+
+            initial.insert(i, entry);
+
+            prev = Some(i)
+        }
+
+        initial
+    }
+
+    /// Checks that loading part of the room from `store` (which holds
+    /// `line_segments_with_state` as "room1") also loads the predecessors that
+    /// are out of range
+    pub(crate) fn check_get_data_from_db_loads_missing_predecessors(store: &mut dyn StateStore) {
+        let initial = line_segments_with_state();
+
+        let (map, max_group_found) =
+            get_data_from_db(store, "room1", Some(4), Some(3), None).unwrap();
+
+        assert_eq!(max_group_found, 7);
+
+        // 5 to 7 are in range, and 3 and 4 are loaded as the predecessors of 5
+        assert_eq!(map.keys().copied().collect::<Vec<_>>(), vec![3, 4, 5, 6, 7]);
+        for (sg, entry) in &map {
+            assert_eq!(entry.in_range, *sg > 4);
+            assert_eq!(entry.prev_state_group, initial[sg].prev_state_group);
+            assert_eq!(entry.state_map, initial[sg].state_map);
+        }
+    }
+
+    /// Checks that compressing "room1" of `store` (which holds
+    /// `line_segments_with_state`) in two chunks keeps the state of every
+    /// group, while saving rows as counted by `row_count`
+    pub(crate) fn check_continue_run_called_twice_keeps_state_of_every_group<S: StateStore>(
+        store: &mut S,
+        row_count: fn(&S) -> usize,
+    ) {
+        let initial = line_segments_with_state();
+        let original_rows = row_count(store);
+
+        let chunk_stats_1 = continue_run(
+            None,
+            7,
+            store,
+            "room1",
+            &[Level::new(3), Level::new(3)],
+            Strategy::Level,
+            &CompressorOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(chunk_stats_1.last_compressed_group, 6);
+
+        let chunk_stats_2 = continue_run(
+            Some(6),
+            7,
+            store,
+            "room1",
+            &chunk_stats_1.new_level_info,
+            Strategy::Level,
+            &CompressorOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(chunk_stats_2.last_compressed_group, 13);
+
+        let (compressed, _) = get_data_from_db(store, "room1", None, None, None).unwrap();
+
+        for sg in initial.keys() {
+            assert_eq!(
+                collapse_state_maps(&compressed, *sg),
+                collapse_state_maps(&initial, *sg)
+            );
+        }
+
+        assert!(row_count(store) < original_rows);
+    }
+
+    #[test]
+    fn get_data_from_db_loads_missing_predecessors_from_store() {
+        let mut store = MemoryStore::default();
+        store.add_room("room1", &line_segments_with_state());
+
+        check_get_data_from_db_loads_missing_predecessors(&mut store);
+    }
+
+    #[test]
+    fn continue_run_called_twice_keeps_state_of_every_group() {
+        let mut store = MemoryStore::default();
+        store.add_room("room1", &line_segments_with_state());
+
+        check_continue_run_called_twice_keeps_state_of_every_group(
+            &mut store,
+            MemoryStore::row_count,
+        );
+    }
+}
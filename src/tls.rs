@@ -0,0 +1,240 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How connections to the database are secured.
+//!
+//! The modes have the same meanings as libpq's `sslmode`. `prefer` (the
+//! default) and `require` use TLS without checking the server's certificate,
+//! which only protects against passive eavesdropping. `verify-ca` checks that
+//! the certificate is signed by a trusted CA (the system's, or those in the
+//! file given as `sslrootcert`) and `verify-full` also checks that it was
+//! issued for the host being connected to.
+
+use std::{fmt, str::FromStr};
+
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use postgres::config::SslMode as PgSslMode;
+use postgres_openssl::MakeTlsConnector;
+
+/// Whether to use TLS, and what to check about the server's certificate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SslMode {
+    /// Never use TLS
+    Disable,
+    /// Use TLS if the server supports it, without checking its certificate
+    #[default]
+    Prefer,
+    /// Always use TLS, without checking the server's certificate
+    Require,
+    /// Always use TLS, and check the server's certificate is signed by a
+    /// trusted CA
+    VerifyCa,
+    /// As `VerifyCa`, and also check the certificate matches the host name
+    VerifyFull,
+}
+
+impl FromStr for SslMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disable" => Ok(SslMode::Disable),
+            "prefer" => Ok(SslMode::Prefer),
+            "require" => Ok(SslMode::Require),
+            "verify-ca" => Ok(SslMode::VerifyCa),
+            "verify-full" => Ok(SslMode::VerifyFull),
+            _ => Err("Unknown sslmode"),
+        }
+    }
+}
+
+impl SslMode {
+    /// Whether the server's certificate is checked
+    fn verifies(self) -> bool {
+        matches!(self, SslMode::VerifyCa | SslMode::VerifyFull)
+    }
+}
+
+/// The TLS settings used when connecting to the database
+///
+/// The files are read when the settings are created, so that a missing or
+/// broken certificate is reported straight away rather than on connecting
+#[derive(Clone)]
+pub struct TlsConfig {
+    // Whether to use TLS, and what to check
+    mode: SslMode,
+    // The connector built from the settings
+    connector: MakeTlsConnector,
+}
+
+impl Default for TlsConfig {
+    /// Uses TLS if the server supports it, without checking its certificate
+    fn default() -> TlsConfig {
+        TlsConfig::new(SslMode::Prefer, None, None, None)
+            .expect("The default TLS settings don't read any files")
+    }
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+impl TlsConfig {
+    /// Creates the TLS settings for connecting to the database
+    ///
+    /// # Arguments
+    ///
+    /// * `mode`        -   Whether to use TLS, and what to check about the
+    ///                     server's certificate
+    /// * `root_cert`   -   A file of PEM CA certificates to trust instead of
+    ///                     the system's (only for `VerifyCa` and `VerifyFull`)
+    /// * `cert`        -   A PEM file with the client certificate to present
+    ///                     to the server
+    /// * `key`         -   A PEM file with the private key for `cert`
+    pub fn new(
+        mode: SslMode,
+        root_cert: Option<&str>,
+        cert: Option<&str>,
+        key: Option<&str>,
+    ) -> Result<TlsConfig, String> {
+        if root_cert.is_some() && !mode.verifies() {
+            return Err(
+                "sslrootcert can only be used with sslmode verify-ca or verify-full".into(),
+            );
+        }
+
+        if cert.is_some() != key.is_some() {
+            return Err("sslcert and sslkey must be given together".into());
+        }
+
+        if mode == SslMode::Disable && cert.is_some() {
+            return Err("sslcert and sslkey can't be used with sslmode disable".into());
+        }
+
+        let mut builder = SslConnector::builder(SslMethod::tls())
+            .map_err(|e| format!("Unable to set up TLS: {}", e))?;
+
+        if mode.verifies() {
+            builder.set_verify(SslVerifyMode::PEER);
+        } else {
+            builder.set_verify(SslVerifyMode::NONE);
+        }
+
+        if let Some(path) = root_cert {
+            builder
+                .set_ca_file(path)
+                .map_err(|e| format!("Unable to read sslrootcert {}: {}", path, e))?;
+        }
+
+        if let (Some(cert), Some(key)) = (cert, key) {
+            builder
+                .set_certificate_chain_file(cert)
+                .map_err(|e| format!("Unable to read sslcert {}: {}", cert, e))?;
+            builder
+                .set_private_key_file(key, SslFiletype::PEM)
+                .map_err(|e| format!("Unable to read sslkey {}: {}", key, e))?;
+            builder
+                .check_private_key()
+                .map_err(|e| format!("sslkey {} doesn't match sslcert {}: {}", key, cert, e))?;
+        }
+
+        let mut connector = MakeTlsConnector::new(builder.build());
+
+        // The host name is checked by default, which verify-ca doesn't do
+        if mode == SslMode::VerifyCa {
+            connector.set_callback(|config, _| {
+                config.set_verify_hostname(false);
+                Ok(())
+            });
+        }
+
+        Ok(TlsConfig { mode, connector })
+    }
+
+    /// Whether to use TLS, and what to check
+    pub fn mode(&self) -> SslMode {
+        self.mode
+    }
+
+    /// The connector to pass to `Client::connect` (or `tokio_postgres::connect`)
+    pub fn connector(&self) -> MakeTlsConnector {
+        self.connector.clone()
+    }
+
+    /// The `sslmode` to connect with. This is None for `Prefer`, so that the
+    /// `sslmode` in the database URL (if any) is left as it is
+    pub(crate) fn pg_ssl_mode(&self) -> Option<PgSslMode> {
+        match self.mode {
+            SslMode::Prefer => None,
+            SslMode::Disable => Some(PgSslMode::Disable),
+            SslMode::Require | SslMode::VerifyCa | SslMode::VerifyFull => Some(PgSslMode::Require),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tls_tests {
+    use std::str::FromStr;
+
+    use super::{SslMode, TlsConfig};
+
+    #[test]
+    fn ssl_mode_from_str_understands_libpq_names() {
+        assert_eq!(SslMode::from_str("disable"), Ok(SslMode::Disable));
+        assert_eq!(SslMode::from_str("prefer"), Ok(SslMode::Prefer));
+        assert_eq!(SslMode::from_str("require"), Ok(SslMode::Require));
+        assert_eq!(SslMode::from_str("verify-ca"), Ok(SslMode::VerifyCa));
+        assert_eq!(SslMode::from_str("verify-full"), Ok(SslMode::VerifyFull));
+        assert!(SslMode::from_str("allow").is_err());
+    }
+
+    #[test]
+    fn tls_config_defaults_to_prefer() {
+        assert_eq!(TlsConfig::default().mode(), SslMode::Prefer);
+    }
+
+    #[test]
+    fn tls_config_errors_if_root_cert_is_not_checked() {
+        let err = TlsConfig::new(SslMode::Require, Some("root.crt"), None, None).unwrap_err();
+
+        assert_eq!(
+            err,
+            "sslrootcert can only be used with sslmode verify-ca or verify-full"
+        );
+    }
+
+    #[test]
+    fn tls_config_errors_if_cert_without_key() {
+        let err = TlsConfig::new(SslMode::VerifyFull, None, Some("client.crt"), None).unwrap_err();
+
+        assert_eq!(err, "sslcert and sslkey must be given together");
+    }
+
+    #[test]
+    fn tls_config_errors_if_root_cert_is_missing() {
+        let err = TlsConfig::new(
+            SslMode::VerifyFull,
+            Some("./this/file/does/not/exist.crt"),
+            None,
+            None,
+        )
+        .unwrap_err();
+
+        assert!(err.starts_with("Unable to read sslrootcert ./this/file/does/not/exist.crt"));
+    }
+}